@@ -11,11 +11,18 @@ impl Plugin for ColliderLinesPlugin {
 }
 
 fn lines(
-    query: Query<(&Collider, &RigidBody, &Transform), With<ColliderLines>>,
+    query: Query<(&Collider, &CollidingEntities, &Transform, Has<MapBase>), With<ColliderLines>>,
+    map_base_q: Query<(), With<MapBase>>,
     mut gizmos: Gizmos,
 ) {
-    for (collider, rigid_body, transform) in query.iter() {
-        let color = if rigid_body.is_colliding {
+    for (collider, colliding_entities, transform, is_map_base) in query.iter() {
+        // Touching the map doesn't count
+        let is_colliding = !is_map_base
+            && colliding_entities
+                .iter()
+                .any(|entity| !map_base_q.contains(*entity));
+
+        let color = if is_colliding {
             Srgba::RED
         } else {
             Srgba::GREEN
@@ -26,7 +33,6 @@ fn lines(
                 translation: transform.translation,
                 rotation: transform.rotation,
                 scale: collider.cuboid,
-                ..default()
             },
             color,
         );
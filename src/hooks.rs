@@ -0,0 +1,75 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    ecs::system::{ReadOnlySystemParam, StaticSystemParam, SystemParamItem},
+    prelude::*,
+};
+
+use crate::{
+    physics::{
+        collect_pairs, filter_one_way_contacts, narrow_phase, update_contact_graph, BroadPhase,
+        NarrowPhase,
+    },
+    Contacts,
+};
+
+/// Collision rules that can't be expressed with [`crate::CollisionLayers`]. Implement it for a
+/// `SystemParam` and add a [`CollisionHooksPlugin`] for that type
+pub trait CollisionHooks: ReadOnlySystemParam + Send + Sync {
+    /// Called for every pair the broad phase finds, returning false skips the pair this step
+    fn filter_pair(&self, _entity_a: Entity, _entity_b: Entity) -> bool {
+        true
+    }
+
+    /// Called for every contact before it's solved. The normal and material coefficients can be
+    /// edited, returning false drops the contact this step
+    fn modify_contacts(&self, _contacts: &mut Contacts) -> bool {
+        true
+    }
+}
+
+/// Runs the hooks of `H` between the collision stages
+pub struct CollisionHooksPlugin<H>(PhantomData<fn() -> H>);
+
+impl<H> Default for CollisionHooksPlugin<H> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<H: CollisionHooks + 'static> Plugin for CollisionHooksPlugin<H>
+where
+    for<'w, 's> SystemParamItem<'w, 's, H>: CollisionHooks,
+{
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            Update,
+            (
+                filter_pairs::<H>.after(collect_pairs).before(narrow_phase),
+                modify_contacts::<H>
+                    .after(narrow_phase)
+                    .after(filter_one_way_contacts)
+                    .before(update_contact_graph),
+            ),
+        );
+    }
+}
+
+fn filter_pairs<H: CollisionHooks>(hooks: StaticSystemParam<H>, mut broad_phase: ResMut<BroadPhase>)
+where
+    for<'w, 's> SystemParamItem<'w, 's, H>: CollisionHooks,
+{
+    let BroadPhase { bodies, pairs, .. } = &mut *broad_phase;
+    pairs.retain(|&(a, b)| hooks.filter_pair(bodies[a].0, bodies[b].0));
+}
+
+fn modify_contacts<H: CollisionHooks>(
+    hooks: StaticSystemParam<H>,
+    mut narrow_phase: ResMut<NarrowPhase>,
+) where
+    for<'w, 's> SystemParamItem<'w, 's, H>: CollisionHooks,
+{
+    narrow_phase
+        .contacts
+        .retain_mut(|contacts| hooks.modify_contacts(contacts));
+}
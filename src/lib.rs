@@ -1,12 +1,25 @@
 mod collider_lines;
 mod controller;
+mod hooks;
 mod physics;
+#[cfg(feature = "picking")]
+mod picking;
+mod solver;
+mod spatial_query;
 
-use bevy::prelude::*;
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
 use collider_lines::ColliderLinesPlugin;
 use controller::ControllerPlugin;
 use physics::PhysicsPlugin;
 
+pub use hooks::{CollisionHooks, CollisionHooksPlugin};
+#[cfg(feature = "picking")]
+pub use picking::{PhysicsPickable, PhysicsPickingPlugin, PhysicsPickingSettings};
+pub use spatial_query::SpatialQuery;
+
 pub struct Bevy3dPhysicsLitePlugin;
 
 impl Plugin for Bevy3dPhysicsLitePlugin {
@@ -42,6 +55,15 @@ impl Default for Damping {
     }
 }
 
+#[derive(Component)]
+pub struct Mass(pub f32);
+
+impl Default for Mass {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
 #[derive(Component)]
 pub struct Speed(pub f32);
 
@@ -61,20 +83,30 @@ impl Default for ColliderColor {
 }
 
 #[derive(Component, Default)]
+#[require(CollidingEntities)]
 pub struct Collider {
     pub cuboid: Vec3,
 }
 
+impl Collider {
+    /// Moments of inertia around the collider's local axes for a given mass
+    pub fn principal_inertia(&self, mass: f32) -> Vec3 {
+        let size = self.cuboid * self.cuboid;
+        Vec3::new(size.y + size.z, size.x + size.z, size.x + size.y) * mass / 12.0
+    }
+}
+
 #[derive(Component, Default)]
+#[require(ContactState)]
 pub struct RigidBody {
     pub damping: Damping,
     pub fixed: bool,
     pub gravity: Gravity,
+    pub mass: Mass,
     pub speed: Speed,
     // pub linear_velocity: Velocity,
     pub linear_velocity: Vec3,
     pub angular_velocity: Vec3,
-    pub is_colliding: bool,
 }
 
 impl RigidBody {
@@ -94,10 +126,467 @@ impl RigidBody {
     }
 
     fn apply_angular_velocity(&mut self, rotation: &mut Quat, delta_time: f32) {
-        let angular_change = Quat::from_rotation_y(self.angular_velocity.y * delta_time); // Example for Y-axis rotation
-        *rotation *= angular_change;
+        let angular_change = Quat::from_scaled_axis(self.angular_velocity * delta_time); // angular velocity is in world space
+        *rotation = (angular_change * *rotation).normalize();
     }
 }
 
 #[derive(Component)]
 pub struct ColliderLines;
+
+/// What a body is standing on or pushing against, classified from its contact normals using
+/// [`PhysicsConfig::max_slope_angle`]
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct ContactState {
+    pub grounded: bool,
+    pub touching_wall: bool,
+    pub touching_ceiling: bool,
+    /// Normal of the flattest ground contact, pointing away from the ground
+    pub ground_normal: Option<Vec3>,
+    pub ground_entity: Option<Entity>,
+    /// Seconds since the body was last grounded, zero while it is
+    pub time_since_grounded: f32,
+}
+
+/// Entities a collider is currently touching, only marked as changed when the set changes
+#[derive(Component, Clone, Debug, Default, PartialEq, Eq, Deref, DerefMut)]
+pub struct CollidingEntities(pub HashSet<Entity>);
+
+/// Welds two bodies together, pulling the anchor points onto each other and keeping the relative
+/// rotation from when the joint was first solved. A body without a [`Collider`] is welded as a
+/// point mass the joint can't rotate. Welded bodies no longer collide with each other
+#[derive(Component, Clone, Copy, Debug)]
+pub struct FixedJoint {
+    pub body_a: Entity,
+    pub body_b: Entity,
+    /// Anchor in `body_a`'s local space
+    pub local_anchor_a: Vec3,
+    /// Anchor in `body_b`'s local space
+    pub local_anchor_b: Vec3,
+}
+
+/// Which layers a collider is on and which layers it collides with. Two colliders only collide
+/// when each one's memberships overlap the other's filters
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionLayers {
+    pub memberships: u32,
+    pub filters: u32,
+}
+
+impl Default for CollisionLayers {
+    fn default() -> Self {
+        Self {
+            memberships: u32::MAX,
+            filters: u32::MAX,
+        }
+    }
+}
+
+impl CollisionLayers {
+    pub fn new<L: PhysicsLayer>(
+        memberships: impl IntoIterator<Item = L>,
+        filters: impl IntoIterator<Item = L>,
+    ) -> Self {
+        Self {
+            memberships: L::all_bits(memberships),
+            filters: L::all_bits(filters),
+        }
+    }
+
+    pub fn from_bits(memberships: u32, filters: u32) -> Self {
+        Self {
+            memberships,
+            filters,
+        }
+    }
+
+    pub fn interacts_with(&self, other: &Self) -> bool {
+        self.memberships & other.filters != 0 && other.memberships & self.filters != 0
+    }
+}
+
+/// Named collision layers, usually implemented for a fieldless enum:
+///
+/// ```
+/// # use bevy_3d_physics_lite::PhysicsLayer;
+/// #[derive(Clone, Copy)]
+/// enum Layer {
+///     Player,
+///     Enemy,
+///     Debris,
+/// }
+///
+/// impl PhysicsLayer for Layer {
+///     fn index(&self) -> u32 {
+///         *self as u32
+///     }
+/// }
+/// ```
+pub trait PhysicsLayer: Sized {
+    /// Bit of the layer in the mask, below 32
+    fn index(&self) -> u32;
+
+    fn to_bits(&self) -> u32 {
+        1 << self.index()
+    }
+
+    fn all_bits(layers: impl IntoIterator<Item = Self>) -> u32 {
+        layers
+            .into_iter()
+            .fold(0, |bits, layer| bits | layer.to_bits())
+    }
+}
+
+/// Makes a collider solid only from one side, like a platform that can be jumped up through.
+/// Bodies that start touching it from any other side pass through until they separate
+#[derive(Component, Clone, Copy, Debug)]
+pub struct OneWay {
+    /// Direction in the collider's local space that bodies can land on it from
+    pub normal: Vec3,
+}
+
+impl Default for OneWay {
+    fn default() -> Self {
+        Self { normal: Vec3::Y }
+    }
+}
+
+/// Makes a collider report overlaps and collision events without pushing anything, it doesn't
+/// need a [`RigidBody`]
+#[derive(Component, Default)]
+pub struct Sensor;
+
+/// How the material coefficients of two colliding bodies are combined. When the two bodies
+/// use different rules, the one declared last here wins
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CombineRule {
+    #[default]
+    Average,
+    Min,
+    Multiply,
+    Max,
+}
+
+impl CombineRule {
+    pub fn combine(self, a: f32, b: f32) -> f32 {
+        match self {
+            CombineRule::Average => (a + b) / 2.0,
+            CombineRule::Min => a.min(b),
+            CombineRule::Multiply => a * b,
+            CombineRule::Max => a.max(b),
+        }
+    }
+}
+
+/// Surface properties of a body, bodies without one use the default material
+#[derive(Component, Clone, Copy, Debug)]
+pub struct PhysicsMaterial {
+    pub restitution: f32,
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+    pub restitution_combine: CombineRule,
+    pub friction_combine: CombineRule,
+}
+
+impl Default for PhysicsMaterial {
+    fn default() -> Self {
+        Self {
+            restitution: 0.3,
+            static_friction: 0.6,
+            dynamic_friction: 0.4,
+            restitution_combine: CombineRule::default(),
+            friction_combine: CombineRule::default(),
+        }
+    }
+}
+
+impl PhysicsMaterial {
+    // Coefficients of a contact between two materials as (restitution, static, dynamic friction)
+    pub(crate) fn combine(&self, other: &Self) -> (f32, f32, f32) {
+        let restitution_rule = self.restitution_combine.max(other.restitution_combine);
+        let friction_rule = self.friction_combine.max(other.friction_combine);
+
+        (
+            restitution_rule.combine(self.restitution, other.restitution),
+            friction_rule.combine(self.static_friction, other.static_friction),
+            friction_rule.combine(self.dynamic_friction, other.dynamic_friction),
+        )
+    }
+}
+
+/// A single point of a contact manifold
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ContactPoint {
+    /// World space position, halfway between the two surfaces
+    pub point: Vec3,
+    /// How deep the colliders overlap at this point
+    pub depth: f32,
+    /// Impulse the solver applied along the normal, carried over to warm start the next step
+    pub normal_impulse: f32,
+    /// Friction impulse the solver applied along the surface
+    pub tangent_impulse: Vec3,
+}
+
+/// Result of the narrow phase between two colliders
+#[derive(Clone, Debug)]
+pub struct Contact {
+    /// Unit normal pointing from the first collider towards the second
+    pub normal: Vec3,
+    /// How deep the colliders overlap along the normal
+    pub penetration: f32,
+    /// Up to four points where the colliders touch
+    pub points: Vec<ContactPoint>,
+}
+
+/// Contact manifold of a colliding pair, the normal points from `entity_a` to `entity_b`. The
+/// pair is always ordered with the lower `Entity` first
+#[derive(Clone, Debug)]
+pub struct Contacts {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    pub contact: Contact,
+    /// Physics step the pair started touching in, see [`ContactGraph::tick`]
+    pub first_contact_tick: u64,
+    /// Combined material coefficients the solver uses for the pair
+    pub restitution: f32,
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+}
+
+/// Every pair of colliders currently in contact, rebuilt by the collision stage each step
+#[derive(Resource, Default)]
+pub struct ContactGraph {
+    contacts: Vec<Contacts>,
+    indices: HashMap<(Entity, Entity), usize>,
+    tick: u64,
+}
+
+impl ContactGraph {
+    // Pairs are stored under the same key whichever way round they are looked up
+    pub(crate) fn key(entity_a: Entity, entity_b: Entity) -> (Entity, Entity) {
+        (entity_a.min(entity_b), entity_a.max(entity_b))
+    }
+
+    pub fn get(&self, entity_a: Entity, entity_b: Entity) -> Option<&Contacts> {
+        let index = self.indices.get(&Self::key(entity_a, entity_b))?;
+        Some(&self.contacts[*index])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Contacts> {
+        self.contacts.iter()
+    }
+
+    /// Every pair the entity is part of
+    pub fn contacts_with(&self, entity: Entity) -> impl Iterator<Item = &Contacts> {
+        self.contacts
+            .iter()
+            .filter(move |contacts| contacts.entity_a == entity || contacts.entity_b == entity)
+    }
+
+    /// Number of physics steps run so far
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    pub(crate) fn contacts(&self) -> &[Contacts] {
+        &self.contacts
+    }
+
+    pub(crate) fn contacts_mut(&mut self) -> &mut [Contacts] {
+        &mut self.contacts
+    }
+
+    // Replace the pairs with the ones found this step, returning last step's
+    pub(crate) fn replace(&mut self, contacts: Vec<Contacts>) -> Vec<Contacts> {
+        self.tick += 1;
+        let previous = std::mem::replace(&mut self.contacts, contacts);
+        self.reindex();
+        previous
+    }
+
+    // Drop every pair involving an entity that no longer has a collider, returning them
+    pub(crate) fn remove_entity(&mut self, entity: Entity) -> Vec<Contacts> {
+        let (removed, kept) = std::mem::take(&mut self.contacts)
+            .into_iter()
+            .partition(|contacts| contacts.entity_a == entity || contacts.entity_b == entity);
+        self.contacts = kept;
+        self.reindex();
+        removed
+    }
+
+    fn reindex(&mut self) {
+        self.indices = self
+            .contacts
+            .iter()
+            .enumerate()
+            .map(|(index, contacts)| (Self::key(contacts.entity_a, contacts.entity_b), index))
+            .collect();
+    }
+}
+
+/// Sent on the step two colliders start touching
+#[derive(Event, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionStarted(pub Entity, pub Entity);
+
+/// Sent on the step two colliders stop touching, or when one of them is despawned
+#[derive(Event, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionEnded(pub Entity, pub Entity);
+
+/// Opts an entity into the [`OnCollisionStart`], [`OnCollisionStay`] and [`OnCollisionEnd`]
+/// observer events
+#[derive(Component, Default)]
+pub struct CollisionEventsEnabled;
+
+/// Triggered on an entity when it starts touching `other`
+#[derive(Event, Clone, Copy, Debug)]
+pub struct OnCollisionStart {
+    pub other: Entity,
+}
+
+/// Triggered on an entity every step after the first that it keeps touching `other`
+#[derive(Event, Clone, Copy, Debug)]
+pub struct OnCollisionStay {
+    pub other: Entity,
+}
+
+/// Triggered on an entity when it stops touching `other`
+#[derive(Event, Clone, Copy, Debug)]
+pub struct OnCollisionEnd {
+    pub other: Entity,
+}
+
+/// Makes a body send [`ContactForceEvent`]s for contacts whose total normal impulse exceeds
+/// the threshold
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ContactForceThreshold(pub f32);
+
+/// How hard two bodies pushed on each other during a step
+#[derive(Event, Clone, Copy, Debug)]
+pub struct ContactForceEvent {
+    pub entity_a: Entity,
+    pub entity_b: Entity,
+    /// Sum of the normal impulses the solver applied over the manifold
+    pub total_normal_impulse: f32,
+    /// Speed the bodies were approaching each other at along the normal, before the contact
+    /// was solved
+    pub normal_speed: f32,
+    /// Contact point, weighted towards the points that pushed hardest
+    pub point: Vec3,
+}
+
+/// Which colliders a [`SpatialQuery`] considers
+#[derive(Clone, Debug)]
+pub struct SpatialQueryFilter {
+    /// Layers a collider has to be a member of to be found
+    pub mask: u32,
+    pub excluded_entities: HashSet<Entity>,
+}
+
+impl Default for SpatialQueryFilter {
+    fn default() -> Self {
+        Self {
+            mask: u32::MAX,
+            excluded_entities: HashSet::new(),
+        }
+    }
+}
+
+impl SpatialQueryFilter {
+    pub fn from_mask(mask: u32) -> Self {
+        Self { mask, ..default() }
+    }
+
+    pub fn with_excluded_entities(mut self, entities: impl IntoIterator<Item = Entity>) -> Self {
+        self.excluded_entities.extend(entities);
+        self
+    }
+
+    pub fn test(&self, entity: Entity, layers: Option<&CollisionLayers>) -> bool {
+        let memberships = layers.map_or(u32::MAX, |layers| layers.memberships);
+        memberships & self.mask != 0 && !self.excluded_entities.contains(&entity)
+    }
+}
+
+/// Where a ray hit a collider
+#[derive(Clone, Copy, Debug)]
+pub struct RayHit {
+    pub entity: Entity,
+    /// Distance along the ray, zero when it starts inside the collider
+    pub distance: f32,
+    pub point: Vec3,
+    /// Normal of the surface that was hit, facing the ray
+    pub normal: Vec3,
+}
+
+/// Where a shape cast first touched a collider
+#[derive(Clone, Copy, Debug)]
+pub struct ShapeHit {
+    pub entity: Entity,
+    /// Distance the shape travelled before touching, zero when it starts overlapping
+    pub distance: f32,
+    /// Closest point on the cast shape, at the time of impact
+    pub point_on_shape: Vec3,
+    /// Closest point on the collider that was hit
+    pub point_on_collider: Vec3,
+    /// Normal of the collider's surface, facing the cast shape
+    pub normal: Vec3,
+}
+
+/// Closest points between two colliders
+#[derive(Clone, Copy, Debug)]
+pub struct ClosestPoints {
+    pub point_a: Vec3,
+    pub point_b: Vec3,
+    /// Distance between the colliders, negative by the overlap depth when they overlap
+    pub distance: f32,
+}
+
+/// Closest point on a collider to a queried point
+#[derive(Clone, Copy, Debug)]
+pub struct PointProjection {
+    pub entity: Entity,
+    /// The queried point itself when it's inside the collider
+    pub point: Vec3,
+    pub is_inside: bool,
+}
+
+/// Tuning for the contact solver
+#[derive(Resource)]
+pub struct PhysicsConfig {
+    /// Passes over the contact velocities each step
+    pub velocity_iterations: usize,
+    /// Passes pushing overlapping bodies apart each step
+    pub position_iterations: usize,
+    /// Approach speed below which contacts don't bounce
+    pub restitution_threshold: f32,
+    /// Penetration left in resting contacts, keeps them from flickering in and out of contact
+    pub penetration_slop: f32,
+    /// Fraction of the penetration beyond the slop removed by each position iteration
+    pub baumgarte: f32,
+    /// Largest distance the position iterations move a contact point apart in one step
+    pub max_correction: f32,
+    /// Steepest slope in radians that still counts as ground, anything steeper is a wall
+    pub max_slope_angle: f32,
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        Self {
+            velocity_iterations: 8,
+            position_iterations: 3,
+            restitution_threshold: 1.0,
+            penetration_slop: 0.005,
+            baumgarte: 0.2,
+            max_correction: 0.2,
+            max_slope_angle: std::f32::consts::FRAC_PI_4,
+        }
+    }
+}
@@ -1,220 +1,1421 @@
-use crate::*;
-use bevy::prelude::*;
+use crate::{solver::*, *};
+use bevy::{
+    ecs::{component::Tick, system::SystemChangeTick},
+    prelude::*,
+    tasks::{ComputeTaskPool, ParallelSlice},
+    utils::{HashMap, HashSet},
+};
+
+// Number of candidate pairs tested by each narrow phase task
+const NARROW_PHASE_CHUNK_SIZE: usize = 64;
+
+// How close a new contact point has to be to last step's to inherit its impulse
+const WARM_START_DISTANCE: f32 = 0.05;
+
+// AABBs this many times wider than the median are kept out of the sorted query list, so one huge
+// collider like the ground doesn't widen every query
+const LARGE_AABB_FACTOR: f32 = 4.0;
 
 pub struct PhysicsPlugin;
 
 impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (apply_physics, collisions).chain()); // applying physics before collision resolution is more accurate
+        app.init_resource::<BroadPhase>()
+            .init_resource::<NarrowPhase>()
+            .init_resource::<OneWayPassThrough>()
+            .init_resource::<JointRestRotations>()
+            .init_resource::<ContactGraph>()
+            .init_resource::<PhysicsConfig>()
+            .add_event::<CollisionStarted>()
+            .add_event::<CollisionEnded>()
+            .add_event::<ContactForceEvent>()
+            .add_systems(
+                Update,
+                (
+                    remove_despawned_contacts,
+                    apply_physics,
+                    collect_pairs,
+                    narrow_phase,
+                    filter_one_way_contacts,
+                    update_contact_graph,
+                    trigger_collision_observers,
+                    solve_contacts,
+                    refresh_broad_phase,
+                    update_contact_states,
+                )
+                    .chain(), // applying physics before collision resolution is more accurate
+            );
     }
 }
 
-// Check if two OBBs (Oriented Bounding Boxes) are colliding
-fn check_obb_collision(
-    half_extents_a: &Vec3,
-    half_extents_b: &Vec3,
-    pos_a: &Vec3,
-    pos_b: &Vec3,
-    rot_a: &Quat,
-    rot_b: &Quat,
-) -> bool {
-    // Compute the orientation axes of OBB A and B
-    let axes_a = [*rot_a * Vec3::X, *rot_a * Vec3::Y, *rot_a * Vec3::Z];
+// Oriented bounding box of a collider in world space
+#[derive(Clone, Copy)]
+pub(crate) struct Obb {
+    pub center: Vec3,
+    pub axes: [Vec3; 3],
+    pub half_extents: Vec3,
+}
+
+impl Obb {
+    pub fn new(collider: &Collider, transform: &Transform) -> Self {
+        let rot = transform.rotation;
+
+        Self {
+            center: transform.translation,
+            axes: [rot * Vec3::X, rot * Vec3::Y, rot * Vec3::Z],
+            half_extents: collider.cuboid / 2.0,
+        }
+    }
+
+    // Half the length of the OBB's projection onto an axis
+    fn radius(&self, axis: Vec3) -> f32 {
+        self.half_extents.x * self.axes[0].dot(axis).abs()
+            + self.half_extents.y * self.axes[1].dot(axis).abs()
+            + self.half_extents.z * self.axes[2].dot(axis).abs()
+    }
+
+    // World space AABB enclosing the OBB, used to cheaply rule out pairs before running SAT
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        let extents = self.axes[0].abs() * self.half_extents.x
+            + self.axes[1].abs() * self.half_extents.y
+            + self.axes[2].abs() * self.half_extents.z;
+
+        (self.center - extents, self.center + extents)
+    }
+
+    pub fn contains(&self, point: Vec3) -> bool {
+        let offset = point - self.center;
+        self.axes
+            .into_iter()
+            .zip(self.half_extents.to_array())
+            .all(|(axis, extent)| offset.dot(axis).abs() <= extent)
+    }
+
+    // Point on or inside the OBB closest to the given one
+    pub fn closest_point(&self, point: Vec3) -> Vec3 {
+        let offset = point - self.center;
+        self.axes
+            .into_iter()
+            .zip(self.half_extents.to_array())
+            .fold(self.center, |closest, (axis, extent)| {
+                closest + axis * offset.dot(axis).clamp(-extent, extent)
+            })
+    }
 
-    let axes_b = [*rot_b * Vec3::X, *rot_b * Vec3::Y, *rot_b * Vec3::Z];
+    // Slab test in the OBB's own frame, returning how far along the ray it enters the OBB and the
+    // normal of the face it enters through. Rays starting inside hit straight away
+    pub fn cast_ray(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_distance: f32,
+    ) -> Option<(f32, Vec3)> {
+        let offset = origin - self.center;
+        let (mut near, mut far) = (0.0, max_distance);
+        let mut normal = -direction;
 
+        for (axis, extent) in self.axes.into_iter().zip(self.half_extents.to_array()) {
+            let start = offset.dot(axis);
+            let speed = direction.dot(axis);
+
+            // Parallel to the slab, the ray is either always between its faces or never
+            if speed.abs() < f32::EPSILON {
+                if start.abs() > extent {
+                    return None;
+                }
+                continue;
+            }
+
+            let enter = (-extent * speed.signum() - start) / speed;
+            let exit = (extent * speed.signum() - start) / speed;
+            if enter > near {
+                near = enter;
+                normal = -axis * speed.signum();
+            }
+            far = far.min(exit);
+
+            if near > far {
+                return None;
+            }
+        }
+
+        Some((near, normal))
+    }
+}
+
+// Projection passes when looking for the closest points of two OBBs, and how little the points
+// have to move in a pass (squared) to stop early
+const CLOSEST_POINTS_ITERATIONS: usize = 64;
+const CLOSEST_POINTS_TOLERANCE: f32 = 1e-10;
+
+// How far past the time of impact a shape cast builds its contact, so it always finds one
+const CAST_CONTACT_DEPTH: f32 = 1e-4;
+
+// Relative margin an axis must win by before it replaces a face of A as the contact axis,
+// which keeps the manifold from flipping between features on nearly equal axes
+const AXIS_SELECTION_BIAS: f32 = 0.95;
+
+// Which features of the two OBBs produced the axis of minimum penetration
+#[derive(Clone, Copy)]
+enum SatAxis {
+    FaceA(usize),
+    FaceB(usize),
+    Edges(usize, usize),
+}
+
+// Separating axis test between two OBBs. Returns the contact along the axis of minimum
+// penetration, with the normal pointing from A to B, or None if a separating axis exists
+pub(crate) fn obb_contact(a: &Obb, b: &Obb) -> Option<Contact> {
     // Compute the translation vector between OBBs
-    let translation = *pos_b - *pos_a;
+    let translation = b.center - a.center;
 
-    // List of axes to test (15 in total)
-    let mut axes = Vec::with_capacity(15);
+    // Project the distance between centers onto axis, the overlap is what's left of the two radii
+    let test_axis = |axis: Vec3| {
+        let d = translation.dot(axis);
+        let penetration = (a.radius(axis) + b.radius(axis)) - d.abs();
+        (penetration >= 0.0).then_some((penetration, axis * d.signum()))
+    };
 
-    // Add face normals of A and B
-    axes.extend_from_slice(&axes_a);
-    axes.extend_from_slice(&axes_b);
+    // Face normals of A and B
+    let mut face_a = (f32::MAX, Vec3::ZERO, SatAxis::FaceA(0));
+    let mut face_b = (f32::MAX, Vec3::ZERO, SatAxis::FaceB(0));
+    for i in 0..3 {
+        let (penetration, normal) = test_axis(a.axes[i])?;
+        if penetration < face_a.0 {
+            face_a = (penetration, normal, SatAxis::FaceA(i));
+        }
+
+        let (penetration, normal) = test_axis(b.axes[i])?;
+        if penetration < face_b.0 {
+            face_b = (penetration, normal, SatAxis::FaceB(i));
+        }
+    }
 
-    // Add cross products of edges
+    // Cross products of edges
+    let mut edges = (f32::MAX, Vec3::ZERO, SatAxis::Edges(0, 0));
     for i in 0..3 {
         for j in 0..3 {
-            let axis = axes_a[i].cross(axes_b[j]);
+            let axis = a.axes[i].cross(b.axes[j]);
             if axis.length_squared() > 1e-6 {
-                axes.push(axis.normalize());
+                let (penetration, normal) = test_axis(axis.normalize())?;
+                if penetration < edges.0 {
+                    edges = (penetration, normal, SatAxis::Edges(i, j));
+                }
             }
         }
     }
 
-    // Now test each axis
-    for axis in axes {
-        // Project OBB A onto axis
-        let r_a = half_extents_a.x * (axes_a[0].dot(axis)).abs()
-            + half_extents_a.y * (axes_a[1].dot(axis)).abs()
-            + half_extents_a.z * (axes_a[2].dot(axis)).abs();
+    // Prefer face contacts, they give a full manifold and are stable frame to frame
+    let mut best = face_a;
+    if face_b.0 < best.0 * AXIS_SELECTION_BIAS {
+        best = face_b;
+    }
+    if edges.0 < best.0 * AXIS_SELECTION_BIAS {
+        best = edges;
+    }
+
+    let (penetration, normal, axis) = best;
+    Some(contact_manifold(a, b, axis, normal, penetration))
+}
+
+// Contact points of two OBBs along one of their separating axes, the normal pointing from A to B
+fn contact_manifold(a: &Obb, b: &Obb, axis: SatAxis, normal: Vec3, penetration: f32) -> Contact {
+    let mut points = match axis {
+        SatAxis::FaceA(i) => clip_faces(a, i, normal, b),
+        SatAxis::FaceB(j) => clip_faces(b, j, -normal, a),
+        SatAxis::Edges(i, j) => vec![edge_contact(a, i, b, j, normal, penetration)],
+    };
+
+    // Clipping can come up empty on grazing contacts, fall back to the box centers
+    if points.is_empty() {
+        points.push(ContactPoint {
+            point: (a.center + b.center) / 2.0,
+            depth: penetration,
+            ..default()
+        });
+    }
+
+    Contact {
+        normal,
+        penetration,
+        points,
+    }
+}
+
+// Candidate separating axes of two OBBs, the face normals of both and the cross products of
+// their edges, along with the features they came from
+fn separating_axes(a: &Obb, b: &Obb) -> impl Iterator<Item = (Vec3, SatAxis)> {
+    let (axes_a, axes_b) = (a.axes, b.axes);
+    let faces_a = (0..3).map(move |i| (axes_a[i], SatAxis::FaceA(i)));
+    let faces_b = (0..3).map(move |j| (axes_b[j], SatAxis::FaceB(j)));
+    let edges = (0..3)
+        .flat_map(|i| (0..3).map(move |j| (i, j)))
+        .filter_map(move |(i, j)| {
+            let axis = axes_a[i].cross(axes_b[j]);
+            (axis.length_squared() > 1e-6).then(|| (axis.normalize(), SatAxis::Edges(i, j)))
+        });
+
+    faces_a.chain(faces_b).chain(edges)
+}
 
-        // Project OBB B onto axis
-        let r_b = half_extents_b.x * (axes_b[0].dot(axis)).abs()
-            + half_extents_b.y * (axes_b[1].dot(axis)).abs()
-            + half_extents_b.z * (axes_b[2].dot(axis)).abs();
+// Swept separating axis test, moving A along `direction` towards a static B. Returns the
+// distance A travels before touching B along with the contact there, or None if it never does
+// within `max_distance`. The Minkowski difference of two boxes only has faces along the usual 15
+// axes, so testing those is exact
+pub(crate) fn obb_cast(
+    a: &Obb,
+    direction: Vec3,
+    max_distance: f32,
+    b: &Obb,
+) -> Option<(f32, Contact)> {
+    let translation = b.center - a.center;
+    let (mut enter, mut exit) = (0.0, max_distance);
+    let mut entering = None;
+
+    for (axis, feature) in separating_axes(a, b) {
+        let d = translation.dot(axis);
+        let radius = a.radius(axis) + b.radius(axis);
+        let speed = direction.dot(axis);
 
-        // Project the distance between centers onto axis
-        let d = translation.dot(axis).abs();
+        // A isn't moving along this axis, so it either always overlaps B on it or never does
+        if speed.abs() < f32::EPSILON {
+            if d.abs() > radius {
+                return None;
+            }
+            continue;
+        }
+
+        // Times the projections start and stop overlapping
+        let first = (d - radius * speed.signum()) / speed;
+        let last = (d + radius * speed.signum()) / speed;
+        if first > enter {
+            enter = first;
+            entering = Some((axis * speed.signum(), feature));
+        }
+        exit = exit.min(last);
 
-        // If projections do not overlap, there is a separating axis
-        if d > r_a + r_b {
-            return false;
+        if enter > exit {
+            return None;
         }
     }
 
-    // No separating axis found
-    true
+    // Nudge A just past the surface so the manifold can be built at the time of impact
+    let moved = Obb {
+        center: a.center + direction * (enter + CAST_CONTACT_DEPTH),
+        ..*a
+    };
+
+    // Build the manifold on the axis A hit along, SAT could pick an axis it only grazes
+    let Some((normal, feature)) = entering else {
+        return Some((enter, obb_contact(&moved, b)?));
+    };
+    let penetration =
+        moved.radius(normal) + b.radius(normal) - (b.center - moved.center).dot(normal);
+
+    Some((
+        enter,
+        contact_manifold(&moved, b, feature, normal, penetration),
+    ))
 }
 
-// Resolve the collision between two entities by adjusting their positions and velocities
-fn resolve_obb_collision(
-    ent_a: (&mut Collider, &mut RigidBody, &mut Transform),
-    ent_b: (&mut Collider, &mut RigidBody, &mut Transform),
-) {
-    let (collider_a, rigid_body_a, transform_a) = ent_a;
-    let (collider_b, rigid_body_b, transform_b) = ent_b;
+// Closest points between two OBBs, along with the distance between them. Overlapping OBBs get a
+// negative distance, the depth they overlap by
+pub(crate) fn obb_closest_points(a: &Obb, b: &Obb) -> (Vec3, Vec3, f32) {
+    if let Some(contact) = obb_contact(a, b) {
+        let count = contact.points.len() as f32;
+        let (point_a, point_b) =
+            contact
+                .points
+                .iter()
+                .fold((Vec3::ZERO, Vec3::ZERO), |(point_a, point_b), point| {
+                    let offset = contact.normal * point.depth / 2.0;
+                    (
+                        point_a + point.point + offset,
+                        point_b + point.point - offset,
+                    )
+                });
 
-    let half_extents_a = collider_a.cuboid / 2.0;
-    let half_extents_b = collider_b.cuboid / 2.0;
+        return (point_a / count, point_b / count, -contact.penetration);
+    }
 
-    let pos_a = transform_a.translation;
-    let pos_b = transform_b.translation;
+    // Alternate projecting onto each box, which converges on the closest pair of points for
+    // two disjoint convex shapes
+    let mut point_a = a.center;
+    let mut point_b = b.closest_point(point_a);
+    for _ in 0..CLOSEST_POINTS_ITERATIONS {
+        let next_a = a.closest_point(point_b);
+        let next_b = b.closest_point(next_a);
+        let moved = next_a.distance_squared(point_a) + next_b.distance_squared(point_b);
 
-    let rot_a = transform_a.rotation;
-    let rot_b = transform_b.rotation;
+        point_a = next_a;
+        point_b = next_b;
+        if moved < CLOSEST_POINTS_TOLERANCE {
+            break;
+        }
+    }
 
-    // Compute the orientation axes of OBB A and B
-    let axes_a = [rot_a * Vec3::X, rot_a * Vec3::Y, rot_a * Vec3::Z];
-    let axes_b = [rot_b * Vec3::X, rot_b * Vec3::Y, rot_b * Vec3::Z];
+    (point_a, point_b, point_a.distance(point_b))
+}
 
-    // Compute the translation vector between OBBs
-    let translation = pos_b - pos_a;
+// Clip the incident box's most opposing face against the reference face. The normal is the
+// reference face's outward normal, pointing towards the incident box
+fn clip_faces(reference: &Obb, axis: usize, normal: Vec3, incident: &Obb) -> Vec<ContactPoint> {
+    let face_center = reference.center + normal * reference.half_extents[axis];
 
-    // List of axes to test (15 in total)
-    let mut axes = Vec::with_capacity(15);
+    // Face of the incident box pointing most against the normal
+    let incident_axis = (0..3)
+        .max_by(|&i, &j| {
+            let alignment_i = incident.axes[i].dot(normal).abs();
+            let alignment_j = incident.axes[j].dot(normal).abs();
+            alignment_i.total_cmp(&alignment_j)
+        })
+        .unwrap_or_default();
+    let incident_normal =
+        incident.axes[incident_axis] * -incident.axes[incident_axis].dot(normal).signum();
+    let incident_center = incident.center + incident_normal * incident.half_extents[incident_axis];
 
-    // Add face normals of A and B
-    axes.extend_from_slice(&axes_a);
-    axes.extend_from_slice(&axes_b);
+    let u_axis = (incident_axis + 1) % 3;
+    let v_axis = (incident_axis + 2) % 3;
+    let u = incident.axes[u_axis] * incident.half_extents[u_axis];
+    let v = incident.axes[v_axis] * incident.half_extents[v_axis];
 
-    // Add cross products of edges
-    for i in 0..3 {
-        for j in 0..3 {
-            let axis = axes_a[i].cross(axes_b[j]);
-            if axis.length_squared() > 1e-6 {
-                axes.push(axis.normalize());
-            }
+    let mut polygon = vec![
+        incident_center + u + v,
+        incident_center - u + v,
+        incident_center - u - v,
+        incident_center + u - v,
+    ];
+
+    // Clip against the four side planes of the reference face
+    for side in [(axis + 1) % 3, (axis + 2) % 3] {
+        let side_normal = reference.axes[side];
+        let offset = face_center.dot(side_normal);
+        let half_extent = reference.half_extents[side];
+
+        polygon = clip_polygon(&polygon, side_normal, offset + half_extent);
+        polygon = clip_polygon(&polygon, -side_normal, -offset + half_extent);
+    }
+
+    // Keep the points below the reference face, placed halfway between the two surfaces
+    let points = polygon
+        .into_iter()
+        .filter_map(|point| {
+            let depth = (face_center - point).dot(normal);
+            (depth >= 0.0).then(|| ContactPoint {
+                point: point + normal * (depth * 0.5),
+                depth,
+                ..default()
+            })
+        })
+        .collect();
+
+    reduce_manifold(points, normal)
+}
+
+// Sutherland-Hodgman clip of a polygon against the half space dot(point, normal) <= offset
+fn clip_polygon(polygon: &[Vec3], normal: Vec3, offset: f32) -> Vec<Vec3> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+
+    for (i, &start) in polygon.iter().enumerate() {
+        let end = polygon[(i + 1) % polygon.len()];
+        let distance_start = start.dot(normal) - offset;
+        let distance_end = end.dot(normal) - offset;
+
+        if distance_start <= 0.0 {
+            clipped.push(start);
+        }
+
+        // The edge crosses the plane, keep the intersection
+        if (distance_start <= 0.0) != (distance_end <= 0.0) {
+            let t = distance_start / (distance_start - distance_end);
+            clipped.push(start + (end - start) * t);
         }
     }
 
-    // Now test each axis and find the axis with minimum penetration
-    let mut min_penetration = f32::MAX;
-    let mut collision_axis = Vec3::ZERO;
+    clipped
+}
+
+// Keep at most four points: the deepest one, the one furthest from it, and the two spanning
+// the largest area on either side of the line between them
+fn reduce_manifold(points: Vec<ContactPoint>, normal: Vec3) -> Vec<ContactPoint> {
+    if points.len() <= 4 {
+        return points;
+    }
+
+    let max_index = |key: &dyn Fn(&ContactPoint) -> f32| {
+        (0..points.len())
+            .max_by(|&i, &j| key(&points[i]).total_cmp(&key(&points[j])))
+            .unwrap_or_default()
+    };
 
-    for axis in axes {
-        // Project OBB A onto axis
-        let r_a = half_extents_a.x * (axes_a[0].dot(axis)).abs()
-            + half_extents_a.y * (axes_a[1].dot(axis)).abs()
-            + half_extents_a.z * (axes_a[2].dot(axis)).abs();
+    let deepest = max_index(&|point| point.depth);
+    let start = points[deepest].point;
 
-        // Project OBB B onto axis
-        let r_b = half_extents_b.x * (axes_b[0].dot(axis)).abs()
-            + half_extents_b.y * (axes_b[1].dot(axis)).abs()
-            + half_extents_b.z * (axes_b[2].dot(axis)).abs();
+    let furthest = max_index(&|point| point.point.distance_squared(start));
+    let end = points[furthest].point;
 
-        // Project the distance between centers onto axis
-        let d = translation.dot(axis);
+    let signed_area = |point: &ContactPoint| (end - start).cross(point.point - start).dot(normal);
+    let left = max_index(&signed_area);
+    let right = max_index(&|point| -signed_area(point));
+
+    let mut indices = vec![deepest, furthest, left, right];
+    indices.sort_unstable();
+    indices.dedup();
+
+    indices.into_iter().map(|i| points[i]).collect()
+}
+
+// Closest points between the two edges that produced an edge on edge axis
+fn edge_contact(
+    a: &Obb,
+    edge_a: usize,
+    b: &Obb,
+    edge_b: usize,
+    normal: Vec3,
+    depth: f32,
+) -> ContactPoint {
+    // Midpoint of the edge on each box that lies furthest towards the other box
+    let edge_center = |obb: &Obb, edge: usize, dir: Vec3| {
+        let mut point = obb.center;
+        for k in (0..3).filter(|&k| k != edge) {
+            point += obb.axes[k] * obb.half_extents[k] * obb.axes[k].dot(dir).signum();
+        }
+        point
+    };
+
+    let center_a = edge_center(a, edge_a, normal);
+    let center_b = edge_center(b, edge_b, -normal);
+    let dir_a = a.axes[edge_a];
+    let dir_b = b.axes[edge_b];
+
+    // Closest points between the two edge lines, clamped to the edges
+    let r = center_a - center_b;
+    let alignment = dir_a.dot(dir_b);
+    let denom = 1.0 - alignment * alignment;
+    let s = if denom > 1e-6 {
+        (alignment * dir_b.dot(r) - dir_a.dot(r)) / denom
+    } else {
+        0.0
+    };
+    let s = s.clamp(-a.half_extents[edge_a], a.half_extents[edge_a]);
+    let t = (alignment * s + dir_b.dot(r)).clamp(-b.half_extents[edge_b], b.half_extents[edge_b]);
+
+    ContactPoint {
+        point: (center_a + dir_a * s + center_b + dir_b * t) / 2.0,
+        depth,
+        ..default()
+    }
+}
+
+// Sweep and prune along the x axis, returning the index pairs whose AABBs overlap and whose
+// layers let them collide
+fn broad_phase(
+    aabbs: &[(Vec3, Vec3)],
+    order: &[usize],
+    layers: &[CollisionLayers],
+) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for (i, &a) in order.iter().enumerate() {
+        let (min_a, max_a) = aabbs[a];
+
+        for &b in &order[i + 1..] {
+            let (min_b, max_b) = aabbs[b];
+
+            // Everything after this starts further along x, so nothing else can overlap
+            if min_b.x > max_a.x {
+                break;
+            }
 
-        let penetration = (r_a + r_b) - d.abs();
+            if min_a.y <= max_b.y
+                && max_a.y >= min_b.y
+                && min_a.z <= max_b.z
+                && max_a.z >= min_b.z
+                && layers[a].interacts_with(&layers[b])
+            {
+                pairs.push((a.min(b), a.max(b)));
+            }
+        }
+    }
 
-        // If projections do not overlap, there is a separating axis (should not happen here)
-        if penetration < 0.0 {
-            // No collision along this axis
-            return;
-        } else if penetration < min_penetration {
-            min_penetration = penetration;
-            collision_axis = axis * d.signum();
+    // Keep the pairs in query order so resolution doesn't depend on the sort
+    pairs.sort_unstable();
+    pairs
+}
+
+// Carry the accumulated impulses over from last step's manifold so the solver can warm start
+fn inherit_impulses(contact: &mut Contact, previous: &Contact) {
+    for point in &mut contact.points {
+        let closest = previous.points.iter().min_by(|a, b| {
+            let distance_a = a.point.distance_squared(point.point);
+            let distance_b = b.point.distance_squared(point.point);
+            distance_a.total_cmp(&distance_b)
+        });
+
+        if let Some(closest) = closest {
+            if closest.point.distance(point.point) < WARM_START_DISTANCE {
+                point.normal_impulse = closest.normal_impulse;
+                point.tangent_impulse = closest.tangent_impulse;
+            }
         }
     }
+}
 
-    // Move objects apart along the collision axis
-    if !rigid_body_a.fixed {
-        transform_a.translation -= collision_axis * (min_penetration * 0.5);
+// Colliders snapshotted by the broad phase, along with the pairs whose bounds overlap
+#[derive(Resource, Default)]
+pub(crate) struct BroadPhase {
+    pub bodies: Vec<(Entity, Obb, PhysicsMaterial)>,
+    pub aabbs: Vec<(Vec3, Vec3)>,
+    // Indices of the bodies sorted by the minimum x of their AABB, leaving out the large ones
+    pub order: Vec<usize>,
+    // Bodies too wide for the sorted list, checked by every query
+    pub large: Vec<usize>,
+    // Widest AABB along x in the sorted list, bounds how far back a search has to look
+    pub max_width: f32,
+    pub pairs: Vec<(usize, usize)>,
+    // When the AABBs were last taken, colliders changed after it aren't reflected in them
+    pub tick: Tick,
+}
+
+impl BroadPhase {
+    // Indices of the bodies whose AABB overlaps the given one, as of the last time they were taken
+    pub fn query_aabb(&self, min: Vec3, max: Vec3) -> impl Iterator<Item = usize> + '_ {
+        // Only bodies starting within one max width before the query box can reach into it
+        let start = self
+            .order
+            .partition_point(|&i| self.aabbs[i].0.x < min.x - self.max_width);
+        let end = self.order.partition_point(|&i| self.aabbs[i].0.x <= max.x);
+
+        self.order[start..end.max(start)]
+            .iter()
+            .chain(&self.large)
+            .copied()
+            .filter(move |&i| {
+                let (min_b, max_b) = self.aabbs[i];
+                min.cmple(max_b).all() && max.cmpge(min_b).all()
+            })
     }
 
-    if !rigid_body_b.fixed {
-        transform_b.translation += collision_axis * (min_penetration * 0.5);
+    // Sort the AABBs for queries, setting the oversized ones aside
+    fn index(&mut self, tick: Tick) {
+        let width = |i: usize| self.aabbs[i].1.x - self.aabbs[i].0.x;
+
+        let mut widths: Vec<f32> = (0..self.aabbs.len()).map(width).collect();
+        let median = if widths.is_empty() {
+            0.0
+        } else {
+            let middle = widths.len() / 2;
+            *widths.select_nth_unstable_by(middle, f32::total_cmp).1
+        };
+
+        let (large, mut order): (Vec<usize>, Vec<usize>) =
+            (0..self.aabbs.len()).partition(|&i| width(i) > median * LARGE_AABB_FACTOR);
+        order.sort_by(|&a, &b| self.aabbs[a].0.x.total_cmp(&self.aabbs[b].0.x));
+
+        self.max_width = order.iter().map(|&i| width(i)).fold(0.0, f32::max);
+        self.order = order;
+        self.large = large;
+        self.tick = tick;
     }
+}
+
+// Contacts found by the narrow phase this step, before they make it into the contact graph
+#[derive(Resource, Default)]
+pub(crate) struct NarrowPhase {
+    pub contacts: Vec<Contacts>,
+}
+
+// Broad phase stage, snapshots the colliders and finds the pairs worth testing
+#[allow(clippy::type_complexity)]
+pub(crate) fn collect_pairs(
+    query: Query<(
+        Entity,
+        &Collider,
+        &Transform,
+        Option<&PhysicsMaterial>,
+        Option<&CollisionLayers>,
+    )>,
+    joint_q: Query<&FixedJoint>,
+    mut broad_phase: ResMut<BroadPhase>,
+    ticks: SystemChangeTick,
+) {
+    // Snapshot the colliders so the narrow phase can read them from any thread
+    broad_phase.bodies = query
+        .iter()
+        .map(|(entity, collider, transform, material, _)| {
+            let material = material.copied().unwrap_or_default();
+            (entity, Obb::new(collider, transform), material)
+        })
+        .collect();
+
+    let layers: Vec<CollisionLayers> = query
+        .iter()
+        .map(|(.., layers)| layers.copied().unwrap_or_default())
+        .collect();
+
+    let aabbs: Vec<(Vec3, Vec3)> = broad_phase
+        .bodies
+        .iter()
+        .map(|(_, obb, _)| obb.aabb())
+        .collect();
 
-    // Adjust velocities (simple elastic collision)
-    let relative_velocity = rigid_body_a.linear_velocity - rigid_body_b.linear_velocity;
-    let velocity_along_axis = relative_velocity.dot(collision_axis);
+    let mut order: Vec<usize> = (0..aabbs.len()).collect();
+    order.sort_by(|&a, &b| aabbs[a].0.x.total_cmp(&aabbs[b].0.x));
 
-    let restitution = 0.8; // Coefficient of restitution (bounciness)
-    let impulse = -(1.0 + restitution) * velocity_along_axis / 2.0;
+    broad_phase.pairs = self::broad_phase(&aabbs, &order, &layers);
 
-    if !rigid_body_a.fixed {
-        rigid_body_a.linear_velocity += impulse * collision_axis;
+    // Put each pair in entity order, query order changes whenever an entity changes archetype and
+    // the pair has to keep its orientation for warm starting and a stable normal
+    let BroadPhase { bodies, pairs, .. } = &mut *broad_phase;
+    for (a, b) in pairs.iter_mut() {
+        if bodies[*a].0 > bodies[*b].0 {
+            std::mem::swap(a, b);
+        }
     }
 
-    if !rigid_body_b.fixed {
-        rigid_body_b.linear_velocity -= impulse * collision_axis;
+    // Welded bodies would only fight their joint
+    if !joint_q.is_empty() {
+        let welded: HashSet<(Entity, Entity)> = joint_q
+            .iter()
+            .map(|joint| {
+                let (a, b) = (joint.body_a, joint.body_b);
+                (a.min(b), a.max(b))
+            })
+            .collect();
+
+        let BroadPhase { bodies, pairs, .. } = &mut *broad_phase;
+        pairs.retain(|&(a, b)| {
+            let (a, b) = (bodies[a].0, bodies[b].0);
+            !welded.contains(&(a.min(b), a.max(b)))
+        });
     }
+    broad_phase.aabbs = aabbs;
+    broad_phase.index(ticks.this_run());
+}
+
+// Take the bounds again once the solver has moved the bodies, so spatial queries search where the
+// colliders ended up this step
+fn refresh_broad_phase(
+    query: Query<(&Collider, &Transform)>,
+    mut broad_phase: ResMut<BroadPhase>,
+    ticks: SystemChangeTick,
+) {
+    let BroadPhase { bodies, aabbs, .. } = &mut *broad_phase;
+    for ((entity, obb, _), aabb) in bodies.iter_mut().zip(aabbs.iter_mut()) {
+        if let Ok((collider, transform)) = query.get(*entity) {
+            *obb = Obb::new(collider, transform);
+            *aabb = obb.aabb();
+        }
+    }
+
+    broad_phase.index(ticks.this_run());
+}
+
+// Narrow phase stage, builds the contact manifolds of the candidate pairs
+pub(crate) fn narrow_phase(broad_phase: Res<BroadPhase>, mut narrow_phase: ResMut<NarrowPhase>) {
+    let bodies = &broad_phase.bodies;
+
+    // Test the candidate pairs in parallel, chunk results come back in the order they were spawned
+    narrow_phase.contacts = broad_phase
+        .pairs
+        .par_chunk_map(
+            ComputeTaskPool::get(),
+            NARROW_PHASE_CHUNK_SIZE,
+            |_, chunk| {
+                chunk
+                    .iter()
+                    .filter_map(|&(a, b)| {
+                        let (entity_a, obb_a, material_a) = &bodies[a];
+                        let (entity_b, obb_b, material_b) = &bodies[b];
+                        let contact = obb_contact(obb_a, obb_b)?;
+                        let (restitution, static_friction, dynamic_friction) =
+                            material_a.combine(material_b);
+
+                        Some(Contacts {
+                            entity_a: *entity_a,
+                            entity_b: *entity_b,
+                            contact,
+                            first_contact_tick: 0,
+                            restitution,
+                            static_friction,
+                            dynamic_friction,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            },
+        )
+        .into_iter()
+        .flatten()
+        .collect();
 }
 
-// Main update system for collision detection and resolution
-fn collisions(
-    mut query: Query<
-        (
-            &mut Collider,
-            &mut RigidBody,
-            &mut Transform,
-            Option<&MapBase>,
-        ),
-        With<Collider>,
-    >,
+// Pairs that started touching a one way collider from the wrong side, ignored until they separate
+#[derive(Resource, Default)]
+pub(crate) struct OneWayPassThrough(HashSet<(Entity, Entity)>);
+
+// Drop the contacts of one way colliders with bodies that aren't landing on them from the allowed
+// side
+pub(crate) fn filter_one_way_contacts(
+    one_way_q: Query<(&OneWay, &Transform)>,
+    rigid_body_q: Query<&RigidBody>,
+    graph: Res<ContactGraph>,
+    mut narrow_phase: ResMut<NarrowPhase>,
+    mut pass_through: ResMut<OneWayPassThrough>,
 ) {
-    for (_, mut rigid_body, _, _) in query.iter_mut() {
-        rigid_body.is_colliding = false;
-    }
-
-    let mut entities = query.iter_combinations_mut();
-    while let Some(
-        [(mut collider_a, mut rb_a, mut transform_a, mapbase_a), (mut collider_b, mut rb_b, mut transform_b, mapbase_b)],
-    ) = entities.fetch_next()
-    {
-        // Check and resolve collisions if they collide
-        if check_obb_collision(
-            &(collider_a.cuboid / 2.0),
-            &(collider_b.cuboid / 2.0),
-            &transform_a.translation,
-            &transform_b.translation,
-            &transform_a.rotation,
-            &transform_b.rotation,
-        ) {
-            if mapbase_a.is_none() && mapbase_b.is_none() {
-                rb_a.is_colliding = true;
-                rb_b.is_colliding = true;
+    if one_way_q.is_empty() {
+        pass_through.0.clear();
+        return;
+    }
+
+    // Velocity a body actually moves at, gravity included
+    let velocity = |entity| {
+        rigid_body_q.get(entity).map_or(Vec3::ZERO, |rigid_body| {
+            if rigid_body.fixed {
+                Vec3::ZERO
+            } else {
+                rigid_body.linear_velocity * rigid_body.speed.0 - Vec3::Y * rigid_body.gravity.0
             }
+        })
+    };
+
+    let previous = std::mem::take(&mut pass_through.0);
+    narrow_phase.contacts.retain(|contacts| {
+        // Normal pointing from the one way collider towards the other body
+        let (platform, other, normal) = if one_way_q.contains(contacts.entity_a) {
+            (
+                contacts.entity_a,
+                contacts.entity_b,
+                contacts.contact.normal,
+            )
+        } else if one_way_q.contains(contacts.entity_b) {
+            (
+                contacts.entity_b,
+                contacts.entity_a,
+                -contacts.contact.normal,
+            )
+        } else {
+            return true;
+        };
+
+        let Ok((one_way, transform)) = one_way_q.get(platform) else {
+            return true;
+        };
+        let allowed_normal = (transform.rotation * one_way.normal).normalize_or_zero();
+        let pair = ContactGraph::key(contacts.entity_a, contacts.entity_b);
+
+        // Pairs already touching only need to stay on the right side, new ones also have to be
+        // moving into the collider
+        let landing = if previous.contains(&pair) {
+            false
+        } else if graph.get(contacts.entity_a, contacts.entity_b).is_some() {
+            normal.dot(allowed_normal) > 0.0
+        } else {
+            let relative_velocity = velocity(other) - velocity(platform);
+            normal.dot(allowed_normal) > 0.0 && relative_velocity.dot(allowed_normal) <= 0.0
+        };
+
+        if !landing {
+            pass_through.0.insert(pair);
+        }
+        landing
+    });
+}
 
-            resolve_obb_collision(
-                (&mut collider_a, &mut rb_a, &mut transform_a),
-                (&mut collider_b, &mut rb_b, &mut transform_b),
+// Moves this step's contacts into the contact graph and reports the pairs that started or
+// stopped touching
+pub(crate) fn update_contact_graph(
+    mut query: Query<(Entity, &mut CollidingEntities)>,
+    mut narrow_phase: ResMut<NarrowPhase>,
+    mut graph: ResMut<ContactGraph>,
+    mut started: EventWriter<CollisionStarted>,
+    mut ended: EventWriter<CollisionEnded>,
+) {
+    let mut contacts = std::mem::take(&mut narrow_phase.contacts);
+
+    let tick = graph.tick() + 1;
+    for contacts in &mut contacts {
+        contacts.first_contact_tick = tick;
+
+        if let Some(previous) = graph.get(contacts.entity_a, contacts.entity_b) {
+            contacts.first_contact_tick = previous.first_contact_tick;
+            inherit_impulses(&mut contacts.contact, &previous.contact);
+        } else {
+            started.send(CollisionStarted(contacts.entity_a, contacts.entity_b));
+        }
+    }
+
+    let mut colliding: HashMap<Entity, HashSet<Entity>> = HashMap::new();
+    for contacts in &contacts {
+        colliding
+            .entry(contacts.entity_a)
+            .or_default()
+            .insert(contacts.entity_b);
+        colliding
+            .entry(contacts.entity_b)
+            .or_default()
+            .insert(contacts.entity_a);
+    }
+
+    // Only touch the components whose set actually changed, so change detection stays useful
+    for (entity, mut colliding_entities) in query.iter_mut() {
+        let entities = colliding.remove(&entity).unwrap_or_default();
+        colliding_entities.set_if_neq(CollidingEntities(entities));
+    }
+
+    // Pairs from last step that weren't found again have separated
+    for previous in graph.replace(contacts) {
+        if graph.get(previous.entity_a, previous.entity_b).is_none() {
+            ended.send(CollisionEnded(previous.entity_a, previous.entity_b));
+        }
+    }
+}
+
+// Forget the pairs of colliders that were removed or despawned since the last step
+fn remove_despawned_contacts(
+    mut removed: RemovedComponents<Collider>,
+    mut graph: ResMut<ContactGraph>,
+    mut ended: EventWriter<CollisionEnded>,
+) {
+    for entity in removed.read() {
+        for contacts in graph.remove_entity(entity) {
+            ended.send(CollisionEnded(contacts.entity_a, contacts.entity_b));
+        }
+    }
+}
+
+// Trigger the observer events on both entities of each pair that opted in
+fn trigger_collision_observers(
+    mut commands: Commands,
+    mut started: EventReader<CollisionStarted>,
+    mut ended: EventReader<CollisionEnded>,
+    graph: Res<ContactGraph>,
+    enabled: Query<(), With<CollisionEventsEnabled>>,
+) {
+    if enabled.is_empty() {
+        started.clear();
+        ended.clear();
+        return;
+    }
+
+    for CollisionStarted(entity_a, entity_b) in started.read() {
+        trigger_pair(&mut commands, &enabled, *entity_a, *entity_b, |other| {
+            OnCollisionStart { other }
+        });
+    }
+
+    // Pairs that started this step already got their start event
+    for contacts in graph.iter() {
+        if contacts.first_contact_tick < graph.tick() {
+            trigger_pair(
+                &mut commands,
+                &enabled,
+                contacts.entity_a,
+                contacts.entity_b,
+                |other| OnCollisionStay { other },
             );
         }
     }
+
+    for CollisionEnded(entity_a, entity_b) in ended.read() {
+        trigger_pair(&mut commands, &enabled, *entity_a, *entity_b, |other| {
+            OnCollisionEnd { other }
+        });
+    }
+}
+
+// Trigger an event on each entity of the pair that opted in, pointing at the other one
+fn trigger_pair<E: Event>(
+    commands: &mut Commands,
+    enabled: &Query<(), With<CollisionEventsEnabled>>,
+    entity_a: Entity,
+    entity_b: Entity,
+    event: impl Fn(Entity) -> E,
+) {
+    if enabled.contains(entity_a) {
+        commands.trigger_targets(event(entity_b), entity_a);
+    }
+    if enabled.contains(entity_b) {
+        commands.trigger_targets(event(entity_a), entity_b);
+    }
+}
+
+// Rotation of each fixed joint's second body relative to its first, recorded the first time the
+// joint is solved
+#[derive(Resource, Default)]
+struct JointRestRotations(HashMap<Entity, Quat>);
+
+// Solver stage, resolves every contact found this step together
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn solve_contacts(
+    mut query: Query<(
+        Option<&mut RigidBody>,
+        &mut Transform,
+        Option<&Collider>,
+        Has<Sensor>,
+    )>,
+    mut graph: ResMut<ContactGraph>,
+    config: Res<PhysicsConfig>,
+    time: Res<Time>,
+    thresholds: Query<&ContactForceThreshold>,
+    mut forces: EventWriter<ContactForceEvent>,
+    joint_q: Query<(Entity, &FixedJoint)>,
+    mut rest_rotations: ResMut<JointRestRotations>,
+    mut warned_joints: Local<HashSet<Entity>>,
+) {
+    // Bodies without a collider can still be welded, as point masses impulses can't rotate
+    let point_mass = Collider::default();
+
+    // Gather the bodies taking part in a contact, each one only once
+    let mut entities = Vec::new();
+    let mut bodies = Vec::new();
+    let mut indices = HashMap::new();
+    let mut body_index = |entity: Entity| {
+        if let Some(&index) = indices.get(&entity) {
+            return Some(index);
+        }
+
+        // Colliders without a rigid body never move
+        let (rigid_body, transform, collider, _) = query.get(entity).ok()?;
+        let collider = collider.unwrap_or(&point_mass);
+        let body = match rigid_body {
+            Some(rigid_body) => SolverBody::new(rigid_body, collider, transform),
+            None => SolverBody::new(
+                &RigidBody {
+                    fixed: true,
+                    ..default()
+                },
+                collider,
+                transform,
+            ),
+        };
+
+        indices.insert(entity, bodies.len());
+        entities.push(entity);
+        bodies.push(body);
+        Some(bodies.len() - 1)
+    };
+
+    let mut manifolds = Vec::with_capacity(graph.len());
+    for (index, contacts) in graph.iter().enumerate() {
+        // Sensors only report overlaps, they're left out of the solver
+        let Ok([(_, _, _, false), (_, _, _, false)]) =
+            query.get_many([contacts.entity_a, contacts.entity_b])
+        else {
+            continue;
+        };
+
+        let (Some(body_a), Some(body_b)) =
+            (body_index(contacts.entity_a), body_index(contacts.entity_b))
+        else {
+            continue;
+        };
+
+        manifolds.push(SolverManifold {
+            index,
+            body_a,
+            body_b,
+            contact: &contacts.contact,
+            restitution: contacts.restitution,
+            static_friction: contacts.static_friction,
+            dynamic_friction: contacts.dynamic_friction,
+        });
+    }
+
+    rest_rotations
+        .0
+        .retain(|&entity, _| joint_q.contains(entity));
+    warned_joints.retain(|&entity| joint_q.contains(entity));
+
+    let mut joints = Vec::new();
+    for (entity, joint) in joint_q.iter() {
+        let Ok([(_, transform_a, _, _), (_, transform_b, _, _)]) =
+            query.get_many([joint.body_a, joint.body_b])
+        else {
+            if warned_joints.insert(entity) {
+                warn!("FixedJoint {entity} skipped, its bodies must be two different entities");
+            }
+            continue;
+        };
+        warned_joints.remove(&entity);
+        let (rotation_a, rotation_b) = (transform_a.rotation, transform_b.rotation);
+
+        let (Some(body_a), Some(body_b)) = (body_index(joint.body_a), body_index(joint.body_b))
+        else {
+            continue;
+        };
+
+        let rest_rotation = *rest_rotations
+            .0
+            .entry(entity)
+            .or_insert_with(|| rotation_a.inverse() * rotation_b);
+
+        joints.push(SolverJoint {
+            body_a,
+            body_b,
+            anchor_a: rotation_a * joint.local_anchor_a,
+            anchor_b: rotation_b * joint.local_anchor_b,
+            rotation_a,
+            rotation_b,
+            rest_rotation,
+        });
+    }
+
+    let mut solver = ContactSolver::new(bodies, &manifolds, &joints, &config);
+
+    solver.warm_start();
+    for _ in 0..config.velocity_iterations {
+        solver.solve_velocities();
+    }
+
+    solver.integrate_velocity_changes(time.delta_secs());
+
+    for _ in 0..config.position_iterations {
+        solver.solve_positions();
+    }
+
+    solver.store_impulses(graph.contacts_mut());
+
+    // The lower threshold of the pair decides whether it's reported
+    if !thresholds.is_empty() {
+        for force in solver.contact_forces() {
+            let contacts = &graph.contacts()[force.index];
+            let threshold = [contacts.entity_a, contacts.entity_b]
+                .into_iter()
+                .filter_map(|entity| thresholds.get(entity).ok())
+                .map(|threshold| threshold.0)
+                .reduce(f32::min);
+
+            if threshold.is_some_and(|threshold| force.total_impulse > threshold) {
+                forces.send(ContactForceEvent {
+                    entity_a: contacts.entity_a,
+                    entity_b: contacts.entity_b,
+                    total_normal_impulse: force.total_impulse,
+                    normal_speed: force.normal_speed,
+                    point: force.point,
+                });
+            }
+        }
+    }
+
+    for (entity, body) in entities.into_iter().zip(solver.bodies) {
+        let Ok((Some(mut rigid_body), mut transform, _, _)) = query.get_mut(entity) else {
+            continue;
+        };
+
+        if !rigid_body.fixed {
+            if rigid_body.speed.0 > 0.0 {
+                rigid_body.linear_velocity = body.velocity_without_support() / rigid_body.speed.0;
+            }
+            rigid_body.angular_velocity = body.angular_velocity;
+            transform.translation += body.position_correction;
+            transform.rotation =
+                (Quat::from_scaled_axis(body.rotation_correction) * transform.rotation).normalize();
+        }
+    }
+}
+
+// Classify every body's contacts as ground, wall or ceiling
+fn update_contact_states(
+    mut query: Query<(Entity, &mut ContactState)>,
+    sensors: Query<(), With<Sensor>>,
+    graph: Res<ContactGraph>,
+    config: Res<PhysicsConfig>,
+    time: Res<Time>,
+) {
+    let min_ground_dot = config.max_slope_angle.cos();
+
+    for (_, mut state) in query.iter_mut() {
+        let time_since_grounded = if state.grounded {
+            0.0
+        } else {
+            state.time_since_grounded
+        };
+
+        *state = ContactState {
+            time_since_grounded: time_since_grounded + time.delta_secs(),
+            ..default()
+        };
+    }
+
+    for contacts in graph.iter() {
+        if sensors.contains(contacts.entity_a) || sensors.contains(contacts.entity_b) {
+            continue;
+        }
+
+        // The contact normal points from A to B, so each body sees the surface facing it
+        let sides = [
+            (
+                contacts.entity_a,
+                contacts.entity_b,
+                -contacts.contact.normal,
+            ),
+            (
+                contacts.entity_b,
+                contacts.entity_a,
+                contacts.contact.normal,
+            ),
+        ];
+
+        for (entity, other, normal) in sides {
+            let Ok((_, mut state)) = query.get_mut(entity) else {
+                continue;
+            };
+
+            let up = normal.dot(Vec3::Y);
+            if up >= min_ground_dot {
+                let flatter = state
+                    .ground_normal
+                    .is_none_or(|ground_normal| up > ground_normal.dot(Vec3::Y));
+                if flatter {
+                    state.ground_normal = Some(normal);
+                    state.ground_entity = Some(other);
+                }
+
+                state.grounded = true;
+                state.time_since_grounded = 0.0;
+            } else if up <= -min_ground_dot {
+                state.touching_ceiling = true;
+            } else {
+                state.touching_wall = true;
+            }
+        }
+    }
 }
 
 // Main system for applying physics
 fn apply_physics(mut query: Query<(&mut RigidBody, &mut Transform)>, time: Res<Time>) {
-    for (mut rigid_body, mut transform) in query.iter_mut() {
-        let delta = time.delta_secs();
+    let delta = time.delta_secs();
+
+    query
+        .par_iter_mut()
+        .for_each(|(mut rigid_body, mut transform)| {
+            rigid_body.apply_damping(); // apply damping BEFORE velocity
+            rigid_body.apply_linear_velocity(&mut transform.translation, delta);
+            rigid_body.apply_angular_velocity(&mut transform.rotation, delta);
+            rigid_body.apply_gravity(&mut transform.translation, delta);
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::time::TimeUpdateStrategy;
+    use std::{
+        f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4, SQRT_2},
+        time::Duration,
+    };
+
+    #[derive(Component)]
+    struct Marker;
+
+    #[test]
+    fn fixed_joint_holds_body_without_collider() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, PhysicsPlugin))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+                1.0 / 60.0,
+            )));
+
+        let base = app
+            .world_mut()
+            .spawn((
+                Transform::from_xyz(0.0, 2.0, 0.0),
+                Collider { cuboid: Vec3::ONE },
+                RigidBody {
+                    fixed: true,
+                    ..default()
+                },
+            ))
+            .id();
+        let turret = app
+            .world_mut()
+            .spawn((Transform::from_xyz(1.0, 2.0, 0.0), RigidBody::default()))
+            .id();
+        app.world_mut().spawn(FixedJoint {
+            body_a: base,
+            body_b: turret,
+            local_anchor_a: Vec3::X * 0.5,
+            local_anchor_b: Vec3::NEG_X * 0.5,
+        });
+
+        for _ in 0..60 {
+            app.update();
+        }
+
+        let position = app.world().get::<Transform>(turret).unwrap().translation;
+        assert!(
+            position.abs_diff_eq(Vec3::new(1.0, 2.0, 0.0), 0.05),
+            "turret sagged to {position}"
+        );
+    }
+
+    fn obb(size: f32, center: Vec3, rotation: Quat) -> Obb {
+        let collider = Collider {
+            cuboid: Vec3::splat(size),
+        };
+        Obb::new(
+            &collider,
+            &Transform::from_translation(center).with_rotation(rotation),
+        )
+    }
+
+    #[test]
+    fn face_contact_is_reduced_to_four_points() {
+        // Turning the top box makes the clipped face an octagon
+        let a = obb(1.0, Vec3::ZERO, Quat::IDENTITY);
+        let b = obb(1.0, Vec3::Y * 0.9, Quat::from_rotation_y(FRAC_PI_4));
+
+        let contact = obb_contact(&a, &b).unwrap();
+        assert!(contact.normal.abs_diff_eq(Vec3::Y, 1e-5));
+        assert!((contact.penetration - 0.1).abs() < 1e-5);
+        assert_eq!(contact.points.len(), 4);
+        for point in &contact.points {
+            assert!((point.depth - 0.1).abs() < 1e-5);
+            assert!((point.point.y - 0.45).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn crossed_edges_give_one_point() {
+        let half_diagonal = FRAC_1_SQRT_2;
+        let a = obb(1.0, Vec3::ZERO, Quat::from_rotation_z(FRAC_PI_4));
+        let b = obb(
+            1.0,
+            Vec3::Y * (2.0 * half_diagonal - 0.05),
+            Quat::from_rotation_x(FRAC_PI_4),
+        );
+
+        let contact = obb_contact(&a, &b).unwrap();
+        assert!(contact.normal.abs_diff_eq(Vec3::Y, 1e-4));
+        assert!((contact.penetration - 0.05).abs() < 1e-4);
+        assert_eq!(contact.points.len(), 1);
+        let expected = Vec3::Y * (half_diagonal - 0.025);
+        assert!(contact.points[0].point.abs_diff_eq(expected, 1e-4));
+    }
+
+    #[test]
+    fn ray_enters_through_facing_side() {
+        let rotation = Quat::from_rotation_y(FRAC_PI_4);
+        let side = rotation * Vec3::X;
+        let obb = obb(2.0, Vec3::ZERO, rotation);
+
+        let (distance, normal) = obb.cast_ray(-side * 5.0, side, 10.0).unwrap();
+        assert!((distance - 4.0).abs() < 1e-5);
+        assert!(normal.abs_diff_eq(-side, 1e-5));
+
+        assert!(obb.cast_ray(-side * 5.0, side, 3.0).is_none());
+        assert!(obb.cast_ray(-side * 5.0, -side, 10.0).is_none());
+
+        // Starting inside hits straight away, facing back along the ray
+        let (distance, normal) = obb.cast_ray(Vec3::ZERO, Vec3::Y, 10.0).unwrap();
+        assert_eq!(distance, 0.0);
+        assert_eq!(normal, -Vec3::Y);
+    }
+
+    #[test]
+    fn shape_cast_stops_on_rotated_face() {
+        let rotation = Quat::from_rotation_z(FRAC_PI_4);
+        let face = rotation * Vec3::X;
+        let target = obb(2.0, Vec3::ZERO, rotation);
+
+        // Moves diagonally in the target's frame, closing on the face slower than it travels
+        let shape = obb(1.0, face * 5.0 - rotation * Vec3::Y * 3.5, rotation);
+        let (distance, contact) = obb_cast(&shape, -Vec3::X, 10.0, &target).unwrap();
+
+        assert!((distance - 3.5 * SQRT_2).abs() < 1e-4);
+        assert!(contact.normal.abs_diff_eq(-face, 1e-4));
+        assert!(!contact.points.is_empty());
+        for point in &contact.points {
+            assert!(point.depth >= 0.0 && point.depth < 1e-3);
+            assert!((point.point.dot(face) - 1.0).abs() < 1e-3);
+        }
+
+        assert!(obb_cast(&shape, -Vec3::X, 4.0, &target).is_none());
+        assert!(obb_cast(&shape, Vec3::X, 10.0, &target).is_none());
+    }
+
+    #[test]
+    fn shape_cast_builds_contact_along_sweep_normal() {
+        let target = obb(2.0, Vec3::ZERO, Quat::IDENTITY);
+
+        // Falls onto the corner while barely overlapping it along x, the axis of least overlap
+        // once it lands
+        let shape = obb(1.0, Vec3::new(1.5 - 1e-6, 3.0, 0.0), Quat::IDENTITY);
+        let (distance, contact) = obb_cast(&shape, -Vec3::Y, 10.0, &target).unwrap();
+
+        assert!((distance - 1.5).abs() < 1e-5);
+        assert_eq!(contact.normal, -Vec3::Y);
+        assert!((contact.penetration - CAST_CONTACT_DEPTH).abs() < 1e-6);
+        for point in &contact.points {
+            assert!((point.depth - CAST_CONTACT_DEPTH).abs() < 1e-6);
+            assert!((point.point.y - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn closest_points_between_crossed_edges() {
+        let half_diagonal = FRAC_1_SQRT_2;
+        let a = obb(1.0, Vec3::ZERO, Quat::from_rotation_z(FRAC_PI_4));
+        let b = obb(1.0, Vec3::Y * 2.0, Quat::from_rotation_x(FRAC_PI_4));
+
+        let (point_a, point_b, distance) = obb_closest_points(&a, &b);
+        assert!(point_a.abs_diff_eq(Vec3::Y * half_diagonal, 1e-4));
+        assert!(point_b.abs_diff_eq(Vec3::Y * (2.0 - half_diagonal), 1e-4));
+        assert!((distance - (2.0 - 2.0 * half_diagonal)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn one_way_pass_through_survives_archetype_change() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, PhysicsPlugin))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+                1.0 / 60.0,
+            )));
+
+        app.world_mut().spawn((
+            Transform::from_xyz(0.0, 3.0, 0.0),
+            Collider {
+                cuboid: Vec3::new(4.0, 1.0, 4.0),
+            },
+            RigidBody {
+                fixed: true,
+                ..default()
+            },
+            OneWay::default(),
+        ));
+
+        // Starts inside the platform below its center and rises through it
+        let body = app
+            .world_mut()
+            .spawn((
+                Transform::from_xyz(0.0, 2.9, 0.0),
+                Collider {
+                    cuboid: Vec3::splat(0.5),
+                },
+                RigidBody {
+                    damping: Damping(0.0),
+                    gravity: Gravity(0.0),
+                    linear_velocity: Vec3::Y * 3.0,
+                    ..default()
+                },
+            ))
+            .id();
+
+        app.update();
+        while app.world().get::<Transform>(body).unwrap().translation.y < 3.1 {
+            app.update();
+        }
+
+        // Past the center and at rest, only the remembered pass-through keeps it from landing
+        let mut entity = app.world_mut().entity_mut(body);
+        entity.get_mut::<RigidBody>().unwrap().linear_velocity = Vec3::ZERO;
+        entity.insert(Marker);
+        let start = entity.get::<Transform>().unwrap().translation.y;
+
+        for _ in 0..5 {
+            app.update();
+        }
 
-        rigid_body.apply_damping(); // apply damping BEFORE velocity
-        rigid_body.apply_linear_velocity(&mut transform.translation, delta);
-        rigid_body.apply_angular_velocity(&mut transform.rotation, delta);
-        rigid_body.apply_gravity(&mut transform.translation, delta);
+        let y = app.world().get::<Transform>(body).unwrap().translation.y;
+        assert!((y - start).abs() < 1e-4, "body was pushed to {y}");
     }
 }
@@ -0,0 +1,79 @@
+use bevy::{
+    picking::{
+        backend::{ray::RayMap, HitData, PointerHits},
+        PickSet,
+    },
+    prelude::*,
+};
+
+use crate::{Sensor, SpatialQuery, SpatialQueryFilter};
+
+/// Picking backend that raycasts against the colliders, so pointer events work on physics bodies
+/// without any meshes. Sensors are never picked
+pub struct PhysicsPickingPlugin;
+
+impl Plugin for PhysicsPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PhysicsPickingSettings>()
+            .add_systems(PreUpdate, update_hits.in_set(PickSet::Backend));
+    }
+}
+
+/// Settings of the [`PhysicsPickingPlugin`]
+#[derive(Resource, Default)]
+pub struct PhysicsPickingSettings {
+    /// Only pick from cameras and colliders with a [`PhysicsPickable`]
+    pub require_markers: bool,
+}
+
+/// Opts a camera or collider into physics picking when
+/// [`PhysicsPickingSettings::require_markers`] is set
+#[derive(Component, Default)]
+pub struct PhysicsPickable;
+
+// Cast every pointer's ray from each camera and report everything it passes through
+fn update_hits(
+    ray_map: Res<RayMap>,
+    settings: Res<PhysicsPickingSettings>,
+    camera_q: Query<(&Camera, Has<PhysicsPickable>)>,
+    target_q: Query<(Option<&PickingBehavior>, Has<PhysicsPickable>, Has<Sensor>)>,
+    spatial_query: SpatialQuery,
+    mut output: EventWriter<PointerHits>,
+) {
+    let filter = SpatialQueryFilter::default();
+
+    for (&ray_id, &ray) in ray_map.map() {
+        let Ok((camera, marked)) = camera_q.get(ray_id.camera) else {
+            continue;
+        };
+        if settings.require_markers && !marked {
+            continue;
+        }
+
+        let picks: Vec<_> = spatial_query
+            .cast_ray_all(ray.origin, ray.direction, f32::MAX, &filter)
+            .into_iter()
+            .filter(|hit| {
+                // Sensors would block the bodies inside them
+                let Ok((behavior, marked, sensor)) = target_q.get(hit.entity) else {
+                    return false;
+                };
+                let hoverable = behavior.is_none_or(|behavior| behavior.is_hoverable);
+                !sensor && hoverable && (marked || !settings.require_markers)
+            })
+            .map(|hit| {
+                let data = HitData::new(
+                    ray_id.camera,
+                    hit.distance,
+                    Some(hit.point),
+                    Some(hit.normal),
+                );
+                (hit.entity, data)
+            })
+            .collect();
+
+        if !picks.is_empty() {
+            output.send(PointerHits::new(ray_id.pointer, picks, camera.order as f32));
+        }
+    }
+}
@@ -0,0 +1,585 @@
+use crate::*;
+use bevy::prelude::*;
+
+// Body state the solver works on, written back to the ECS once all iterations are done
+pub(crate) struct SolverBody {
+    pub inverse_mass: f32,
+    // World space inverse inertia tensor
+    pub inverse_inertia: Mat3,
+    // Center of mass at the time the contacts were generated
+    pub center: Vec3,
+    // Velocity the body actually moves at, `linear_velocity` scaled by `speed`
+    pub velocity: Vec3,
+    pub angular_velocity: Vec3,
+    // Gravity moves bodies at a constant rate rather than accelerating them. The solver still
+    // has to see it, otherwise resting contacts carry no normal impulse for friction to work with
+    pub gravity_velocity: Vec3,
+    // Velocities the body was already moved with this step, before any contacts were solved
+    initial_velocity: Vec3,
+    initial_angular_velocity: Vec3,
+    pub position_correction: Vec3,
+    // Small angle rotation, as a scaled axis
+    pub rotation_correction: Vec3,
+}
+
+impl SolverBody {
+    pub fn new(rigid_body: &RigidBody, collider: &Collider, transform: &Transform) -> Self {
+        let inverse_mass = if rigid_body.fixed || rigid_body.mass.0 <= 0.0 {
+            0.0
+        } else {
+            1.0 / rigid_body.mass.0
+        };
+
+        let inverse_inertia = if inverse_mass > 0.0 {
+            let inertia = collider.principal_inertia(rigid_body.mass.0);
+            let inverse = Vec3::select(inertia.cmpgt(Vec3::ZERO), inertia.recip(), Vec3::ZERO);
+            let rotation = Mat3::from_quat(transform.rotation);
+            rotation * Mat3::from_diagonal(inverse) * rotation.transpose()
+        } else {
+            Mat3::ZERO
+        };
+
+        let gravity_velocity = if rigid_body.fixed {
+            Vec3::ZERO
+        } else {
+            Vec3::NEG_Y * rigid_body.gravity.0
+        };
+
+        let velocity = rigid_body.linear_velocity * rigid_body.speed.0;
+
+        Self {
+            inverse_mass,
+            inverse_inertia,
+            center: transform.translation,
+            velocity,
+            angular_velocity: rigid_body.angular_velocity,
+            gravity_velocity,
+            initial_velocity: velocity,
+            initial_angular_velocity: rigid_body.angular_velocity,
+            position_correction: Vec3::ZERO,
+            rotation_correction: Vec3::ZERO,
+        }
+    }
+
+    // Velocity of the point at `anchor` from the center, without the gravity drift
+    fn point_velocity(&self, anchor: Vec3) -> Vec3 {
+        self.velocity + self.angular_velocity.cross(anchor)
+    }
+
+    fn apply_impulse(&mut self, impulse: Vec3, anchor: Vec3) {
+        self.velocity += impulse * self.inverse_mass;
+        self.angular_velocity += self.inverse_inertia * anchor.cross(impulse);
+    }
+
+    // Inverse of the mass the body resists an impulse along `direction` at `anchor` with
+    fn inverse_effective_mass(&self, anchor: Vec3, direction: Vec3) -> f32 {
+        let angular = (self.inverse_inertia * anchor.cross(direction)).cross(anchor);
+        self.inverse_mass + angular.dot(direction)
+    }
+
+    // How far the point at `anchor` has been moved by the corrections so far
+    fn point_correction(&self, anchor: Vec3) -> Vec3 {
+        self.position_correction + self.rotation_correction.cross(anchor)
+    }
+
+    fn apply_correction(&mut self, correction: Vec3, anchor: Vec3) {
+        self.position_correction += correction * self.inverse_mass;
+        self.rotation_correction += self.inverse_inertia * anchor.cross(correction);
+    }
+
+    // Matrix mapping an impulse at `anchor` to the change in that point's velocity
+    fn point_mass_matrix(&self, anchor: Vec3) -> Mat3 {
+        let skew = skew_symmetric(anchor);
+        Mat3::from_diagonal(Vec3::splat(self.inverse_mass)) - skew * self.inverse_inertia * skew
+    }
+
+    // Velocity to keep on the body after solving. The part of the contact response that only
+    // held the body up against gravity is dropped, gravity drifts it again next step anyway
+    pub fn velocity_without_support(&self) -> Vec3 {
+        let gravity = self.gravity_velocity.length();
+        if gravity <= 0.0 {
+            return self.velocity;
+        }
+
+        let up = -self.gravity_velocity / gravity;
+        let support = (self.velocity - self.initial_velocity)
+            .dot(up)
+            .clamp(0.0, gravity);
+
+        self.velocity - up * support
+    }
+}
+
+// Cross product with `vector` as a matrix
+fn skew_symmetric(vector: Vec3) -> Mat3 {
+    Mat3::from_cols(
+        Vec3::new(0.0, vector.z, -vector.y),
+        Vec3::new(-vector.z, 0.0, vector.x),
+        Vec3::new(vector.y, -vector.x, 0.0),
+    )
+}
+
+// Inverse of a mass matrix, or None when neither body can respond
+fn inverse_mass_matrix(matrix: Mat3) -> Option<Mat3> {
+    (matrix.determinant().abs() > f32::EPSILON).then(|| matrix.inverse())
+}
+
+// Impulse at the anchors and angular impulse that cancel a fixed joint's relative linear and
+// angular velocity together. Solving both blocks at once (through the Schur complement of the
+// angular block) matters for bodies anchored far from their center, where solving them one
+// after the other barely converges
+fn solve_joint(
+    body_a: &SolverBody,
+    body_b: &SolverBody,
+    joint: &SolverJoint,
+    linear: Vec3,
+    angular: Vec3,
+) -> Option<(Vec3, Vec3)> {
+    let linear_mass =
+        body_a.point_mass_matrix(joint.anchor_a) + body_b.point_mass_matrix(joint.anchor_b);
+    let coupling = body_b.inverse_inertia * skew_symmetric(joint.anchor_b)
+        + body_a.inverse_inertia * skew_symmetric(joint.anchor_a);
+    let angular_mass = body_a.inverse_inertia + body_b.inverse_inertia;
+
+    // Neither body can rotate, only the anchors need holding together
+    let Some(inverse_angular_mass) = inverse_mass_matrix(angular_mass) else {
+        let impulse = inverse_mass_matrix(linear_mass)? * -linear;
+        return Some((impulse, Vec3::ZERO));
+    };
+
+    let schur = linear_mass - coupling.transpose() * inverse_angular_mass * coupling;
+    let impulse = inverse_mass_matrix(schur)?
+        * (-linear + coupling.transpose() * inverse_angular_mass * angular);
+    let angular_impulse = inverse_angular_mass * (-angular - coupling * impulse);
+
+    Some((impulse, angular_impulse))
+}
+
+// A fixed joint along with the indices of its two bodies in the solver
+pub(crate) struct SolverJoint {
+    pub body_a: usize,
+    pub body_b: usize,
+    // World space anchors relative to each body's center
+    pub anchor_a: Vec3,
+    pub anchor_b: Vec3,
+    pub rotation_a: Quat,
+    pub rotation_b: Quat,
+    // Rotation of B relative to A the joint holds
+    pub rest_rotation: Quat,
+}
+
+// A contact manifold along with the indices of its two bodies in the solver
+pub(crate) struct SolverManifold<'a> {
+    // Position of the pair in the contact graph
+    pub index: usize,
+    pub body_a: usize,
+    pub body_b: usize,
+    pub contact: &'a Contact,
+    pub restitution: f32,
+    pub static_friction: f32,
+    pub dynamic_friction: f32,
+}
+
+struct ConstraintPoint {
+    // Contact point relative to each body's center
+    anchor_a: Vec3,
+    anchor_b: Vec3,
+    depth: f32,
+    normal_mass: f32,
+    normal_impulse: f32,
+    tangent_mass: Vec2,
+    tangent_impulse: Vec2,
+    velocity_bias: f32,
+    // Normal velocity before any impulses were applied, negative when approaching
+    normal_velocity: f32,
+    // Distance pushed apart by the position iterations so far this step
+    position_correction: f32,
+}
+
+struct ContactConstraint {
+    index: usize,
+    body_a: usize,
+    body_b: usize,
+    normal: Vec3,
+    tangents: [Vec3; 2],
+    static_friction: f32,
+    dynamic_friction: f32,
+    points: Vec<ConstraintPoint>,
+}
+
+// Velocity of B relative to A at a contact point, including the gravity drift
+fn relative_velocity(
+    bodies: &[SolverBody],
+    body_a: usize,
+    body_b: usize,
+    point: &ConstraintPoint,
+) -> Vec3 {
+    let body_a = &bodies[body_a];
+    let body_b = &bodies[body_b];
+
+    (body_b.point_velocity(point.anchor_b) + body_b.gravity_velocity)
+        - (body_a.point_velocity(point.anchor_a) + body_a.gravity_velocity)
+}
+
+// Apply an impulse to B at the contact point and the opposite one to A
+fn apply_impulse(
+    bodies: &mut [SolverBody],
+    body_a: usize,
+    body_b: usize,
+    point: &ConstraintPoint,
+    impulse: Vec3,
+) {
+    bodies[body_a].apply_impulse(-impulse, point.anchor_a);
+    bodies[body_b].apply_impulse(impulse, point.anchor_b);
+}
+
+// Mass the pair resists an impulse along `direction` at the contact point with
+fn effective_mass(
+    body_a: &SolverBody,
+    body_b: &SolverBody,
+    anchor_a: Vec3,
+    anchor_b: Vec3,
+    direction: Vec3,
+) -> f32 {
+    let inverse_mass = body_a.inverse_effective_mass(anchor_a, direction)
+        + body_b.inverse_effective_mass(anchor_b, direction);
+
+    if inverse_mass > 0.0 {
+        1.0 / inverse_mass
+    } else {
+        0.0
+    }
+}
+
+// Sequential impulse solver over every contact manifold found in a step
+pub(crate) struct ContactSolver<'a> {
+    pub bodies: Vec<SolverBody>,
+    constraints: Vec<ContactConstraint>,
+    joints: &'a [SolverJoint],
+    penetration_slop: f32,
+    baumgarte: f32,
+    max_correction: f32,
+}
+
+impl<'a> ContactSolver<'a> {
+    pub fn new(
+        bodies: Vec<SolverBody>,
+        manifolds: &[SolverManifold],
+        joints: &'a [SolverJoint],
+        config: &PhysicsConfig,
+    ) -> Self {
+        let constraints = manifolds
+            .iter()
+            .map(|manifold| {
+                let (body_a, body_b) = (&bodies[manifold.body_a], &bodies[manifold.body_b]);
+                let normal = manifold.contact.normal;
+                let (tangent, bitangent) = normal.any_orthonormal_pair();
+
+                let points = manifold
+                    .contact
+                    .points
+                    .iter()
+                    .map(|point| {
+                        let anchor_a = point.point - body_a.center;
+                        let anchor_b = point.point - body_b.center;
+                        let mass = |direction| {
+                            effective_mass(body_a, body_b, anchor_a, anchor_b, direction)
+                        };
+
+                        // Bounce off the approach speed measured before any impulses are
+                        // applied, gravity drift is left out so resting bodies don't hop
+                        let normal_velocity = (body_b.point_velocity(anchor_b)
+                            - body_a.point_velocity(anchor_a))
+                        .dot(normal);
+                        let velocity_bias = if -normal_velocity > config.restitution_threshold {
+                            -manifold.restitution * normal_velocity
+                        } else {
+                            0.0
+                        };
+
+                        ConstraintPoint {
+                            anchor_a,
+                            anchor_b,
+                            depth: point.depth,
+                            normal_mass: mass(normal),
+                            normal_impulse: point.normal_impulse,
+                            tangent_mass: Vec2::new(mass(tangent), mass(bitangent)),
+                            tangent_impulse: Vec2::new(
+                                point.tangent_impulse.dot(tangent),
+                                point.tangent_impulse.dot(bitangent),
+                            ),
+                            velocity_bias,
+                            normal_velocity,
+                            position_correction: 0.0,
+                        }
+                    })
+                    .collect();
+
+                ContactConstraint {
+                    index: manifold.index,
+                    body_a: manifold.body_a,
+                    body_b: manifold.body_b,
+                    normal,
+                    tangents: [tangent, bitangent],
+                    static_friction: manifold.static_friction,
+                    dynamic_friction: manifold.dynamic_friction,
+                    points,
+                }
+            })
+            .collect();
+
+        Self {
+            bodies,
+            constraints,
+            joints,
+            penetration_slop: config.penetration_slop,
+            baumgarte: config.baumgarte,
+            max_correction: config.max_correction,
+        }
+    }
+
+    // Reapply last step's impulses so resting contacts start close to their solution
+    pub fn warm_start(&mut self) {
+        for constraint in &self.constraints {
+            for point in &constraint.points {
+                let impulse = constraint.normal * point.normal_impulse
+                    + constraint.tangents[0] * point.tangent_impulse.x
+                    + constraint.tangents[1] * point.tangent_impulse.y;
+                apply_impulse(
+                    &mut self.bodies,
+                    constraint.body_a,
+                    constraint.body_b,
+                    point,
+                    impulse,
+                );
+            }
+        }
+    }
+
+    pub fn solve_velocities(&mut self) {
+        self.solve_joint_velocities();
+
+        for constraint in &mut self.constraints {
+            let (body_a, body_b) = (constraint.body_a, constraint.body_b);
+
+            // Friction goes first, bounded by the normal impulse of the previous iteration
+            for point in &mut constraint.points {
+                let relative_velocity = relative_velocity(&self.bodies, body_a, body_b, point);
+                let lambda = -point.tangent_mass
+                    * Vec2::new(
+                        relative_velocity.dot(constraint.tangents[0]),
+                        relative_velocity.dot(constraint.tangents[1]),
+                    );
+
+                // Stick while inside the static friction cone, otherwise slide along its
+                // dynamic edge
+                let mut accumulated = point.tangent_impulse + lambda;
+                let length = accumulated.length();
+                if length > constraint.static_friction * point.normal_impulse {
+                    accumulated *= constraint.dynamic_friction * point.normal_impulse / length;
+                }
+
+                let delta = accumulated - point.tangent_impulse;
+                point.tangent_impulse = accumulated;
+
+                let impulse = constraint.tangents[0] * delta.x + constraint.tangents[1] * delta.y;
+                apply_impulse(&mut self.bodies, body_a, body_b, point, impulse);
+            }
+
+            for point in &mut constraint.points {
+                let relative_velocity = relative_velocity(&self.bodies, body_a, body_b, point);
+                let normal_velocity = relative_velocity.dot(constraint.normal);
+
+                // Clamp the accumulated impulse rather than each increment, so earlier
+                // iterations can be partially undone
+                let lambda = -point.normal_mass * (normal_velocity - point.velocity_bias);
+                let accumulated = (point.normal_impulse + lambda).max(0.0);
+                let delta = accumulated - point.normal_impulse;
+                point.normal_impulse = accumulated;
+
+                let impulse = constraint.normal * delta;
+                apply_impulse(&mut self.bodies, body_a, body_b, point, impulse);
+            }
+        }
+    }
+
+    // Bodies were integrated before the contacts were solved, so redo this step's motion with the
+    // solved velocities. This is what keeps resting and sticking bodies from drifting
+    pub fn integrate_velocity_changes(&mut self, delta: f32) {
+        for body in &mut self.bodies {
+            body.position_correction += (body.velocity - body.initial_velocity) * delta;
+            body.rotation_correction +=
+                (body.angular_velocity - body.initial_angular_velocity) * delta;
+        }
+    }
+
+    // Push overlapping bodies apart directly, without adding energy to their velocities. The slop
+    // is left in so resting bodies stay in contact instead of separating every other step
+    pub fn solve_positions(&mut self) {
+        self.solve_joint_positions();
+
+        for constraint in &mut self.constraints {
+            for point in &mut constraint.points {
+                let body_a = &self.bodies[constraint.body_a];
+                let body_b = &self.bodies[constraint.body_b];
+
+                // Penetration left after the corrections made so far
+                let separation = (body_b.point_correction(point.anchor_b)
+                    - body_a.point_correction(point.anchor_a))
+                .dot(constraint.normal);
+                let depth = point.depth - separation;
+                // The limit covers the whole step, not each iteration
+                let correction = (self.baumgarte * (depth - self.penetration_slop))
+                    .min(self.max_correction - point.position_correction);
+                if correction <= 0.0 {
+                    continue;
+                }
+                point.position_correction += correction;
+
+                let mass = effective_mass(
+                    body_a,
+                    body_b,
+                    point.anchor_a,
+                    point.anchor_b,
+                    constraint.normal,
+                );
+
+                let correction = constraint.normal * (correction * mass);
+                self.bodies[constraint.body_a].apply_correction(-correction, point.anchor_a);
+                self.bodies[constraint.body_b].apply_correction(correction, point.anchor_b);
+            }
+        }
+    }
+
+    // Stop the anchors moving apart and the bodies rotating relative to each other
+    fn solve_joint_velocities(&mut self) {
+        for joint in self.joints {
+            let (body_a, body_b) = (&self.bodies[joint.body_a], &self.bodies[joint.body_b]);
+
+            let relative_velocity = (body_b.point_velocity(joint.anchor_b)
+                + body_b.gravity_velocity)
+                - (body_a.point_velocity(joint.anchor_a) + body_a.gravity_velocity);
+            let relative_angular_velocity = body_b.angular_velocity - body_a.angular_velocity;
+
+            let Some((impulse, angular_impulse)) = solve_joint(
+                body_a,
+                body_b,
+                joint,
+                relative_velocity,
+                relative_angular_velocity,
+            ) else {
+                continue;
+            };
+
+            let body_a = &mut self.bodies[joint.body_a];
+            body_a.apply_impulse(-impulse, joint.anchor_a);
+            body_a.angular_velocity -= body_a.inverse_inertia * angular_impulse;
+
+            let body_b = &mut self.bodies[joint.body_b];
+            body_b.apply_impulse(impulse, joint.anchor_b);
+            body_b.angular_velocity += body_b.inverse_inertia * angular_impulse;
+        }
+    }
+
+    // Pull the anchors back together and the bodies back to their rest rotation, a fraction of
+    // the error per iteration like the contacts
+    fn solve_joint_positions(&mut self) {
+        for joint in self.joints {
+            let (body_a, body_b) = (&self.bodies[joint.body_a], &self.bodies[joint.body_b]);
+
+            let separation =
+                (body_b.center + joint.anchor_b + body_b.point_correction(joint.anchor_b))
+                    - (body_a.center + joint.anchor_a + body_a.point_correction(joint.anchor_a));
+
+            // Rotation taking B from where the joint wants it to where it is, as a scaled axis
+            let rotation_a = Quat::from_scaled_axis(body_a.rotation_correction) * joint.rotation_a;
+            let rotation_b = Quat::from_scaled_axis(body_b.rotation_correction) * joint.rotation_b;
+            let mut rotation_error = rotation_b * (rotation_a * joint.rest_rotation).inverse();
+            if rotation_error.w < 0.0 {
+                rotation_error = -rotation_error;
+            }
+
+            let Some((correction, angular_correction)) = solve_joint(
+                body_a,
+                body_b,
+                joint,
+                (separation * self.baumgarte).clamp_length_max(self.max_correction),
+                rotation_error.to_scaled_axis() * self.baumgarte,
+            ) else {
+                continue;
+            };
+
+            let body_a = &mut self.bodies[joint.body_a];
+            body_a.apply_correction(-correction, joint.anchor_a);
+            body_a.rotation_correction -= body_a.inverse_inertia * angular_correction;
+
+            let body_b = &mut self.bodies[joint.body_b];
+            body_b.apply_correction(correction, joint.anchor_b);
+            body_b.rotation_correction += body_b.inverse_inertia * angular_correction;
+        }
+    }
+
+    // Keep the accumulated impulses on the manifolds for warm starting the next step
+    pub fn store_impulses(&self, contacts: &mut [Contacts]) {
+        for constraint in &self.constraints {
+            let contacts = &mut contacts[constraint.index];
+            for (point, contact_point) in constraint.points.iter().zip(&mut contacts.contact.points)
+            {
+                contact_point.normal_impulse = point.normal_impulse;
+                contact_point.tangent_impulse = constraint.tangents[0] * point.tangent_impulse.x
+                    + constraint.tangents[1] * point.tangent_impulse.y;
+            }
+        }
+    }
+
+    // Total normal impulse each manifold ended up with this step
+    pub fn contact_forces(&self) -> impl Iterator<Item = ContactForce> + '_ {
+        self.constraints.iter().map(|constraint| {
+            let center = self.bodies[constraint.body_a].center;
+            let total_impulse: f32 = constraint
+                .points
+                .iter()
+                .map(|point| point.normal_impulse)
+                .sum();
+
+            // Points pushing harder count for more, fall back to the plain average when
+            // nothing pushed at all
+            let point = if total_impulse > 0.0 {
+                constraint
+                    .points
+                    .iter()
+                    .map(|point| (center + point.anchor_a) * point.normal_impulse)
+                    .sum::<Vec3>()
+                    / total_impulse
+            } else {
+                constraint
+                    .points
+                    .iter()
+                    .map(|point| center + point.anchor_a)
+                    .sum::<Vec3>()
+                    / constraint.points.len().max(1) as f32
+            };
+
+            let normal_speed = constraint
+                .points
+                .iter()
+                .map(|point| -point.normal_velocity)
+                .fold(0.0, f32::max);
+
+            ContactForce {
+                index: constraint.index,
+                total_impulse,
+                normal_speed,
+                point,
+            }
+        })
+    }
+}
+
+// Solved normal impulse of a manifold, see `ContactForceEvent`
+pub(crate) struct ContactForce {
+    pub index: usize,
+    pub total_impulse: f32,
+    pub normal_speed: f32,
+    pub point: Vec3,
+}
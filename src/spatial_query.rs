@@ -0,0 +1,431 @@
+use bevy::{
+    ecs::system::{SystemChangeTick, SystemParam},
+    prelude::*,
+    tasks::{ComputeTaskPool, ParallelSlice},
+};
+
+use crate::{
+    physics::{obb_cast, obb_closest_points, obb_contact, BroadPhase, Obb},
+    *,
+};
+
+// Number of line of sight checks run by each task
+const LINE_OF_SIGHT_CHUNK_SIZE: usize = 64;
+
+/// Queries against the colliders in the world
+#[derive(SystemParam)]
+pub struct SpatialQuery<'w, 's> {
+    colliders: Query<
+        'w,
+        's,
+        (
+            Entity,
+            &'static Collider,
+            &'static Transform,
+            Option<&'static CollisionLayers>,
+        ),
+    >,
+    broad_phase: Res<'w, BroadPhase>,
+    sensors: Query<'w, 's, (), With<Sensor>>,
+    changes: Query<'w, 's, (Entity, Ref<'static, Transform>, Ref<'static, Collider>)>,
+    ticks: SystemChangeTick,
+}
+
+impl SpatialQuery<'_, '_> {
+    /// Closest collider hit by the ray within `max_distance`
+    pub fn cast_ray(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_distance: f32,
+        filter: &SpatialQueryFilter,
+    ) -> Option<RayHit> {
+        self.ray_hits(origin, direction, max_distance, filter)
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Every collider hit by the ray within `max_distance`, closest first
+    pub fn cast_ray_all(
+        &self,
+        origin: Vec3,
+        direction: Dir3,
+        max_distance: f32,
+        filter: &SpatialQueryFilter,
+    ) -> Vec<RayHit> {
+        let mut hits: Vec<RayHit> = self
+            .ray_hits(origin, direction, max_distance, filter)
+            .collect();
+        hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        hits
+    }
+
+    /// First collider a shape moving from `origin` along `direction` touches within
+    /// `max_distance`
+    pub fn cast_shape(
+        &self,
+        shape: &Collider,
+        origin: Vec3,
+        rotation: Quat,
+        direction: Dir3,
+        max_distance: f32,
+        filter: &SpatialQueryFilter,
+    ) -> Option<ShapeHit> {
+        let obb = Obb::new(
+            shape,
+            &Transform::from_translation(origin).with_rotation(rotation),
+        );
+
+        self.colliders
+            .iter()
+            .filter(|(entity, _, _, layers)| filter.test(*entity, *layers))
+            .filter_map(|(entity, collider, transform, _)| {
+                let target = Obb::new(collider, transform);
+                let (distance, contact) = obb_cast(&obb, *direction, max_distance, &target)?;
+
+                // Contact points sit halfway between the two surfaces, the normal points from
+                // the shape to the collider
+                let count = contact.points.len() as f32;
+                let (point_on_shape, point_on_collider) = contact.points.iter().fold(
+                    (Vec3::ZERO, Vec3::ZERO),
+                    |(on_shape, on_collider), point| {
+                        let offset = contact.normal * point.depth / 2.0;
+                        (
+                            on_shape + point.point + offset,
+                            on_collider + point.point - offset,
+                        )
+                    },
+                );
+
+                Some(ShapeHit {
+                    entity,
+                    distance,
+                    point_on_shape: point_on_shape / count,
+                    point_on_collider: point_on_collider / count,
+                    normal: -contact.normal,
+                })
+            })
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Colliders containing the point. Overlap queries search the bounds left by the last physics
+    /// step and test colliders moved, resized or spawned since then one by one, so changing a lot
+    /// of them outside the physics step makes the queries slower
+    pub fn intersect_point(&self, point: Vec3, filter: &SpatialQueryFilter) -> Vec<Entity> {
+        self.intersect(point, point, filter, |obb| obb.contains(point))
+    }
+
+    /// Colliders intersecting the axis aligned box between `min` and `max`
+    pub fn intersect_aabb(&self, min: Vec3, max: Vec3, filter: &SpatialQueryFilter) -> Vec<Entity> {
+        let aabb = Obb {
+            center: (min + max) / 2.0,
+            axes: [Vec3::X, Vec3::Y, Vec3::Z],
+            half_extents: (max - min) / 2.0,
+        };
+
+        self.intersect(min, max, filter, |obb| obb_contact(&aabb, obb).is_some())
+    }
+
+    /// Colliders intersecting the shape placed at `transform`
+    pub fn intersect_shape(
+        &self,
+        shape: &Collider,
+        transform: &Transform,
+        filter: &SpatialQueryFilter,
+    ) -> Vec<Entity> {
+        let shape = Obb::new(shape, transform);
+        let (min, max) = shape.aabb();
+
+        self.intersect(min, max, filter, |obb| obb_contact(&shape, obb).is_some())
+    }
+
+    /// Distance between the colliders of two entities, negative when they overlap
+    pub fn distance(&self, entity_a: Entity, entity_b: Entity) -> Option<f32> {
+        self.closest_points(entity_a, entity_b)
+            .map(|closest_points| closest_points.distance)
+    }
+
+    /// Closest points between the colliders of two entities. When they overlap the points are
+    /// the deepest ones of each collider inside the other
+    pub fn closest_points(&self, entity_a: Entity, entity_b: Entity) -> Option<ClosestPoints> {
+        let (_, collider_a, transform_a, _) = self.colliders.get(entity_a).ok()?;
+        let (_, collider_b, transform_b, _) = self.colliders.get(entity_b).ok()?;
+
+        let (point_a, point_b, distance) = obb_closest_points(
+            &Obb::new(collider_a, transform_a),
+            &Obb::new(collider_b, transform_b),
+        );
+
+        Some(ClosestPoints {
+            point_a,
+            point_b,
+            distance,
+        })
+    }
+
+    /// Closest point on any collider to `point`
+    pub fn project_point(
+        &self,
+        point: Vec3,
+        filter: &SpatialQueryFilter,
+    ) -> Option<PointProjection> {
+        self.colliders
+            .iter()
+            .filter(|(entity, _, _, layers)| filter.test(*entity, *layers))
+            .map(|(entity, collider, transform, _)| {
+                let obb = Obb::new(collider, transform);
+                let is_inside = obb.contains(point);
+                PointProjection {
+                    entity,
+                    point: if is_inside {
+                        point
+                    } else {
+                        obb.closest_point(point)
+                    },
+                    is_inside,
+                }
+            })
+            .min_by(|a, b| {
+                let distance_a = a.point.distance_squared(point);
+                let distance_b = b.point.distance_squared(point);
+                distance_a.total_cmp(&distance_b)
+            })
+    }
+
+    /// Whether each source can see its target, checked in parallel. The segment between them is
+    /// raised by `eye_height` at both ends and ignores the source, the target and sensors. Pairs
+    /// with a missing entity can't see each other
+    pub fn line_of_sight(
+        &self,
+        pairs: &[(Entity, Entity)],
+        eye_height: f32,
+        filter: &SpatialQueryFilter,
+    ) -> Vec<bool> {
+        let changed = self.changed_colliders();
+        pairs
+            .par_chunk_map(
+                ComputeTaskPool::get(),
+                LINE_OF_SIGHT_CHUNK_SIZE,
+                |_, chunk| {
+                    chunk
+                        .iter()
+                        .map(|&(source, target)| {
+                            self.segment_clear(source, target, eye_height, filter, &changed)
+                        })
+                        .collect::<Vec<_>>()
+                },
+            )
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn segment_clear(
+        &self,
+        source: Entity,
+        target: Entity,
+        eye_height: f32,
+        filter: &SpatialQueryFilter,
+        changed: &[Entity],
+    ) -> bool {
+        let (Ok((.., source_transform, _)), Ok((.., target_transform, _))) =
+            (self.colliders.get(source), self.colliders.get(target))
+        else {
+            return false;
+        };
+
+        let eye = Vec3::Y * eye_height;
+        let start = source_transform.translation + eye;
+        let end = target_transform.translation + eye;
+        let Ok(direction) = Dir3::new(end - start) else {
+            return true;
+        };
+        let length = start.distance(end);
+
+        // Only the colliders whose bounds overlap the segment's can block it
+        self.candidates(start.min(end), start.max(end), changed)
+            .all(|entity| {
+                if entity == source || entity == target || self.sensors.contains(entity) {
+                    return true;
+                }
+
+                let Ok((_, collider, transform, layers)) = self.colliders.get(entity) else {
+                    return true;
+                };
+
+                let obb = Obb::new(collider, transform);
+                !filter.test(entity, layers) || obb.cast_ray(start, *direction, length).is_none()
+            })
+    }
+
+    // Colliders changed since the broad phase last took their bounds, sorted so they can be
+    // looked up
+    fn changed_colliders(&self) -> Vec<Entity> {
+        let (last_run, this_run) = (self.broad_phase.tick, self.ticks.this_run());
+        let mut changed: Vec<Entity> = self
+            .changes
+            .iter()
+            .filter(|(_, transform, collider)| {
+                transform.last_changed().is_newer_than(last_run, this_run)
+                    || collider.last_changed().is_newer_than(last_run, this_run)
+            })
+            .map(|(entity, ..)| entity)
+            .collect();
+        changed.sort_unstable();
+        changed
+    }
+
+    // Colliders that may overlap the box. The broad phase's bounds are only trusted for the ones
+    // that haven't changed since, the rest are all candidates
+    fn candidates<'a>(
+        &'a self,
+        min: Vec3,
+        max: Vec3,
+        changed: &'a [Entity],
+    ) -> impl Iterator<Item = Entity> + 'a {
+        self.broad_phase
+            .query_aabb(min, max)
+            .map(|i| self.broad_phase.bodies[i].0)
+            .filter(|entity| changed.binary_search(entity).is_err())
+            .chain(changed.iter().copied())
+    }
+
+    // Narrow the colliders down with the broad phase, then test where the rest are now
+    fn intersect(
+        &self,
+        min: Vec3,
+        max: Vec3,
+        filter: &SpatialQueryFilter,
+        test: impl Fn(&Obb) -> bool,
+    ) -> Vec<Entity> {
+        let changed = self.changed_colliders();
+        self.candidates(min, max, &changed)
+            .filter_map(|entity| {
+                let (_, collider, transform, layers) = self.colliders.get(entity).ok()?;
+                let obb = Obb::new(collider, transform);
+                (filter.test(entity, layers) && test(&obb)).then_some(entity)
+            })
+            .collect()
+    }
+
+    fn ray_hits<'a>(
+        &'a self,
+        origin: Vec3,
+        direction: Dir3,
+        max_distance: f32,
+        filter: &'a SpatialQueryFilter,
+    ) -> impl Iterator<Item = RayHit> + 'a {
+        self.colliders
+            .iter()
+            .filter(|(entity, _, _, layers)| filter.test(*entity, *layers))
+            .filter_map(move |(entity, collider, transform, _)| {
+                let obb = Obb::new(collider, transform);
+                let (distance, normal) = obb.cast_ray(origin, *direction, max_distance)?;
+
+                Some(RayHit {
+                    entity,
+                    distance,
+                    point: origin + direction * distance,
+                    normal,
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::{ecs::system::RunSystemOnce, time::TimeUpdateStrategy};
+    use std::time::Duration;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, crate::physics::PhysicsPlugin))
+            .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(
+                1.0 / 60.0,
+            )));
+        app
+    }
+
+    fn cube(app: &mut App, position: Vec3) -> Entity {
+        let collider = Collider { cuboid: Vec3::ONE };
+        app.world_mut()
+            .spawn((Transform::from_translation(position), collider))
+            .id()
+    }
+
+    #[test]
+    fn intersect_finds_colliders_changed_since_the_step() {
+        let mut app = app();
+        let ground = app
+            .world_mut()
+            .spawn((
+                Transform::from_xyz(0.0, -0.5, 0.0),
+                Collider {
+                    cuboid: Vec3::new(50.0, 1.0, 50.0),
+                },
+            ))
+            .id();
+        for x in 0..4 {
+            cube(&mut app, Vec3::new(x as f32 * 2.0, 0.5, 5.0));
+        }
+        let moved = cube(&mut app, Vec3::new(10.0, 0.5, 0.0));
+
+        app.update();
+        app.world_mut()
+            .get_mut::<Transform>(moved)
+            .unwrap()
+            .translation
+            .x = -10.0;
+        let spawned = cube(&mut app, Vec3::new(20.0, 0.5, 0.0));
+
+        let (old, new, late, under) = app
+            .world_mut()
+            .run_system_once(|query: SpatialQuery| {
+                let filter = SpatialQueryFilter::default();
+                (
+                    query.intersect_point(Vec3::new(10.0, 0.5, 0.0), &filter),
+                    query.intersect_point(Vec3::new(-10.0, 0.5, 0.0), &filter),
+                    query.intersect_point(Vec3::new(20.0, 0.5, 0.0), &filter),
+                    query.intersect_point(Vec3::new(-24.0, -0.5, 0.0), &filter),
+                )
+            })
+            .unwrap();
+
+        assert!(old.is_empty());
+        assert_eq!(new, vec![moved]);
+        assert_eq!(late, vec![spawned]);
+
+        // The ground is far wider than the rest and still found from its edge
+        assert_eq!(under, vec![ground]);
+    }
+
+    #[test]
+    fn line_of_sight_sees_blockers_moved_since_the_step() {
+        let mut app = app();
+        let source = cube(&mut app, Vec3::new(-5.0, 0.5, 0.0));
+        let target = cube(&mut app, Vec3::new(5.0, 0.5, 0.0));
+        let wall = cube(&mut app, Vec3::new(0.0, 0.5, 10.0));
+
+        let line_of_sight = |app: &mut App| {
+            app.world_mut()
+                .run_system_once(move |query: SpatialQuery| {
+                    query.line_of_sight(&[(source, target)], 0.0, &default())[0]
+                })
+                .unwrap()
+        };
+
+        app.update();
+        assert!(line_of_sight(&mut app));
+
+        // Moved into the way after the physics step took its bounds
+        app.world_mut()
+            .get_mut::<Transform>(wall)
+            .unwrap()
+            .translation
+            .z = 0.0;
+        assert!(!line_of_sight(&mut app));
+
+        app.update();
+        assert!(!line_of_sight(&mut app));
+    }
+}